@@ -0,0 +1,314 @@
+use nom::types::CompleteStr;
+use nom::{named, map, ws, Context, Err, ErrorKind, IResult};
+use crate::assembler::{AssemblerError, AssemblerErrorKind, Position, SyntaxError, Token};
+use crate::assembler::label_parsers::{label_declaration, label_reference};
+use crate::assembler::operand_parsers::{integer_literal, integer_operand};
+use crate::assembler::symbols::{Section, SymbolTable};
+
+/// A directive that lays out bytes in the read-only data section.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Directive {
+    /// The bytes of a string followed by a terminating zero byte.
+    Asciiz { text: String },
+    Byte { values: Vec<DataValue> },
+    Half { values: Vec<DataValue> },
+    Word { values: Vec<DataValue> },
+    /// Zero bytes up to the next multiple of `boundary`.
+    Align { boundary: u32 },
+}
+
+/// A value given to `.byte`, `.half` or `.word`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DataValue {
+    Integer { value: i32 },
+    /// The offset of a label, resolved when the data is encoded.
+    Label { name: String },
+}
+
+/// A directive in the data section, optionally labelled.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataDeclaration {
+    pub label: Option<Token>,
+    pub directive: Directive,
+    /// Where the label, the directive and each value were written.
+    pub label_position: Position,
+    pub position: Position,
+    pub value_positions: Vec<Position>,
+}
+
+impl DataDeclaration {
+    /// Number of bytes the directive lays out when it starts at `offset`.
+    pub fn size(&self, offset: usize) -> usize {
+        match &self.directive {
+            Directive::Asciiz { text } => text.len() + 1,
+            Directive::Byte { values } => values.len(),
+            Directive::Half { values } => 2 * values.len(),
+            Directive::Word { values } => 4 * values.len(),
+            Directive::Align { boundary } => offset.next_multiple_of(*boundary as usize) - offset,
+        }
+    }
+
+    /// Moves the positions from the text the declaration was parsed from to
+    /// where that text sits in the source.
+    pub fn place(&mut self, line: usize, indent: usize) {
+        self.label_position = self.label_position.placed(line, indent);
+        self.position = self.position.placed(line, indent);
+        for position in &mut self.value_positions {
+            *position = position.placed(line, indent);
+        }
+    }
+
+    /// Encodes the directive placed at `offset`, resolving label values
+    /// against `symbols`. Values are big-endian, like instruction operands.
+    pub fn to_bytes(&self, offset: usize, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        let (values, name, width) = match &self.directive {
+            Directive::Asciiz { text } => {
+                let mut bytes = text.as_bytes().to_vec();
+                bytes.push(0);
+                return Ok(bytes);
+            }
+            Directive::Align { .. } => return Ok(vec![0; self.size(offset)]),
+            Directive::Byte { values } => (values, "byte", 1),
+            Directive::Half { values } => (values, "half", 2),
+            Directive::Word { values } => (values, "word", 4),
+        };
+        let mut bytes = vec![];
+        for (index, value) in values.iter().enumerate() {
+            let position = self.value_positions.get(index).copied().unwrap_or(self.position);
+            let value = match value {
+                DataValue::Label { name } => symbols.symbol_value(name).ok_or_else(|| {
+                    AssemblerError::new(position, AssemblerErrorKind::UndefinedLabel { name: name.clone() })
+                })? as i32,
+                DataValue::Integer { value } => *value,
+            };
+            let bits = 8 * width;
+            if width < 4 && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+                let kind = AssemblerErrorKind::ValueOutOfRange { value, directive: name.to_string() };
+                return Err(AssemblerError::new(position, kind));
+            }
+            bytes.extend_from_slice(&value.to_be_bytes()[4 - width..]);
+        }
+        Ok(bytes)
+    }
+}
+
+named!(
+    value<CompleteStr, DataValue>,
+    ws!(
+        alt!(
+            map!(integer_literal, |value| DataValue::Integer { value }) |
+            map!(label_reference, |name| DataValue::Label { name })
+        )
+    )
+);
+
+/// The largest boundary `.align` accepts.
+pub const MAX_ALIGNMENT: u32 = 4096;
+
+const DIRECTIVE: &str = "a data directive such as `.asciiz` or `.word`";
+const VALUE: &str = "an integer such as `#1` or a label such as `@table`";
+
+/// Parses `.data` or `.code`, switching the section that follows.
+pub fn section_directive(input: CompleteStr) -> IResult<CompleteStr, Section> {
+    let Some((rest, name)) = directive_name(&input) else {
+        return Err(Err::Error(Context::Code(input, ErrorKind::Tag)));
+    };
+    match name.to_ascii_lowercase().as_str() {
+        "data" => Ok((CompleteStr(rest), Section::Data)),
+        "code" => Ok((CompleteStr(rest), Section::Code)),
+        _ => Err(Err::Error(Context::Code(input, ErrorKind::Tag))),
+    }
+}
+
+/// Parses an optional label declaration followed by a data directive.
+/// Positions are relative to `input`, taken as line 1.
+pub fn data_declaration(input: &str) -> Result<(&str, DataDeclaration), SyntaxError<'_>> {
+    let (rest, label) = match label_declaration(CompleteStr(input)) {
+        Ok((rest, label)) => (rest.0, Some(label)),
+        Err(_) => (input, None),
+    };
+    let position = Position::within(input, rest);
+    let error = |rest, expected: &str| Err(SyntaxError { rest, expected: expected.to_string() });
+    let Some((after_name, name)) = directive_name(rest) else {
+        return error(rest, DIRECTIVE);
+    };
+    let mut value_positions = vec![];
+    let (rest, directive) = match name.to_ascii_lowercase().as_str() {
+        "asciiz" => {
+            let (rest, text) = string_literal(after_name.trim_start()).ok_or(SyntaxError {
+                rest: after_name,
+                expected: "a string such as `\"text\"`".to_string(),
+            })?;
+            (rest, Directive::Asciiz { text })
+        }
+        "byte" | "half" | "word" => {
+            let (rest, values) = values(after_name, |rest| value_positions.push(Position::within(input, rest)))?;
+            let directive = match name.to_ascii_lowercase().as_str() {
+                "byte" => Directive::Byte { values },
+                "half" => Directive::Half { values },
+                _ => Directive::Word { values },
+            };
+            (rest, directive)
+        }
+        "align" => match integer_operand(CompleteStr(after_name)) {
+            Ok((rest, Token::IntegerOperand { value }))
+                if value > 0 && value as u32 <= MAX_ALIGNMENT && (value as u32).is_power_of_two() =>
+            {
+                (rest.0, Directive::Align { boundary: value as u32 })
+            }
+            _ => return error(after_name, &format!("a power of two up to `#{MAX_ALIGNMENT}` such as `#4`")),
+        },
+        _ => return error(rest, DIRECTIVE),
+    };
+    let label_position = Position::within(input, input);
+    Ok((rest, DataDeclaration { label, directive, label_position, position, value_positions }))
+}
+
+/// Splits `.` followed by a directive name off `input`, skipping leading
+/// whitespace.
+fn directive_name(input: &str) -> Option<(&str, &str)> {
+    let name = input.trim_start().strip_prefix('.')?;
+    let length = name.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(name.len());
+    if length == 0 {
+        return None;
+    }
+    Some((&name[length..], &name[..length]))
+}
+
+/// Parses one or more integer or label values, separated by whitespace or
+/// commas, calling `at` with the input each value starts at.
+fn values<'a>(input: &'a str, mut at: impl FnMut(&'a str)) -> Result<(&'a str, Vec<DataValue>), SyntaxError<'a>> {
+    let expected = || SyntaxError { rest: input, expected: VALUE.to_string() };
+    let Ok((rest, first)) = value(CompleteStr(input)) else {
+        return Err(expected());
+    };
+    at(input);
+    let mut rest = rest.0;
+    let mut values = vec![first];
+    loop {
+        let after_comma = rest.trim_start().strip_prefix(',');
+        let next = after_comma.unwrap_or(rest);
+        match value(CompleteStr(next)) {
+            Ok((remaining, parsed)) => {
+                at(next);
+                values.push(parsed);
+                rest = remaining.0;
+            }
+            Err(_) if after_comma.is_some() => return Err(SyntaxError { rest: next, expected: VALUE.to_string() }),
+            Err(_) => return Ok((rest, values)),
+        }
+    }
+}
+
+/// Parses a double-quoted string with the same escapes as character literals.
+fn string_literal(input: &str) -> Option<(&str, String)> {
+    let quoted = input.strip_prefix('"')?;
+    let mut text = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((index, c)) = chars.next() {
+        let c = match c {
+            '"' => return Some((&quoted[index + 1..], text)),
+            '\n' => return None,
+            '\\' => match chars.next() {
+                Some((_, 'n')) => '\n',
+                Some((_, 't')) => '\t',
+                Some((_, 'r')) => '\r',
+                Some((_, '0')) => '\0',
+                Some((_, c)) if c == '\\' || c == '"' => c,
+                _ => return None,
+            },
+            c => c,
+        };
+        text.push(c);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbols::Symbol;
+
+    #[test]
+    fn test_parse_section_directives() {
+        assert_eq!(section_directive(CompleteStr("  .data\nhlt")), Ok((CompleteStr("\nhlt"), Section::Data)));
+        assert_eq!(section_directive(CompleteStr(".CODE")), Ok((CompleteStr(""), Section::Code)));
+        assert!(section_directive(CompleteStr(".byte #1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_data_declarations() {
+        let (rest, parsed) = data_declaration("greeting: .asciiz \"hi \\\"you\\\"\\n\"\n.byte #1").unwrap();
+        assert_eq!(parsed.label, Some(Token::LabelDeclaration { name: "greeting".to_string() }));
+        assert_eq!(parsed.directive, Directive::Asciiz { text: "hi \"you\"\n".to_string() });
+
+        let (rest, parsed) = data_declaration(rest).unwrap();
+        assert_eq!(parsed.directive, Directive::Byte { values: vec![DataValue::Integer { value: 1 }] });
+        assert_eq!(rest, "");
+
+        let (_, parsed) = data_declaration(".word #1, @table #-2\nla $1 @table").unwrap();
+        assert_eq!(parsed.directive, Directive::Word {
+            values: vec![
+                DataValue::Integer { value: 1 },
+                DataValue::Label { name: "table".to_string() },
+                DataValue::Integer { value: -2 },
+            ]
+        });
+
+        let (_, parsed) = data_declaration(".align #4").unwrap();
+        assert_eq!(parsed.directive, Directive::Align { boundary: 4 });
+    }
+
+    #[test]
+    fn test_data_declaration_bytes() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("start", 8));
+        let encode = |source: &str, offset: usize| {
+            let (_, parsed) = data_declaration(source).unwrap();
+            let bytes = parsed.to_bytes(offset, &symbols).map_err(|err| err.kind);
+            if let Ok(bytes) = &bytes {
+                assert_eq!(bytes.len(), parsed.size(offset));
+            }
+            bytes
+        };
+        assert_eq!(encode(".asciiz \"ok\"", 0), Ok(vec![b'o', b'k', 0]));
+        assert_eq!(encode(".byte #-1 #255 #'a'", 0), Ok(vec![255, 255, 97]));
+        assert_eq!(encode(".half #-2, #0x1234", 0), Ok(vec![0xFF, 0xFE, 0x12, 0x34]));
+        assert_eq!(encode(".word @start #-1", 0), Ok(vec![0, 0, 0, 8, 0xFF, 0xFF, 0xFF, 0xFF]));
+        assert_eq!(encode(".align #4", 5), Ok(vec![0, 0, 0]));
+        assert_eq!(encode(".align #4", 8), Ok(vec![]));
+        assert_eq!(encode(".byte #256", 0), Err(AssemblerErrorKind::ValueOutOfRange { value: 256, directive: "byte".to_string() }));
+        assert_eq!(encode(".half #-32769", 0), Err(AssemblerErrorKind::ValueOutOfRange { value: -32769, directive: "half".to_string() }));
+        assert_eq!(encode(".word @end", 0), Err(AssemblerErrorKind::UndefinedLabel { name: "end".to_string() }));
+    }
+
+    #[test]
+    fn test_data_declaration_positions() {
+        let (_, mut parsed) = data_declaration("table: .half #1,  @end").unwrap();
+        parsed.place(3, 2);
+        assert_eq!(parsed.label_position, Position { line: 3, column: 3 });
+        assert_eq!(parsed.position, Position { line: 3, column: 10 });
+        assert_eq!(parsed.value_positions, vec![Position { line: 3, column: 16 }, Position { line: 3, column: 21 }]);
+    }
+
+    #[test]
+    fn test_parse_bad_data_declarations() {
+        let expected = |source| data_declaration(source).map(|_| ()).map_err(|err| (err.rest.trim_start(), err.expected));
+        assert_eq!(expected(".space #4"), Err((".space #4", DIRECTIVE.to_string())));
+        assert_eq!(expected(".byte #1, $2"), Err(("$2", VALUE.to_string())));
+        let boundary = || "a power of two up to `#4096` such as `#4`".to_string();
+        assert_eq!(expected(".align #0"), Err(("#0", boundary())));
+        assert_eq!(expected(".align #12"), Err(("#12", boundary())));
+        assert_eq!(expected(".align #8192"), Err(("#8192", boundary())));
+        assert_eq!(expected(".align #-4"), Err(("#-4", boundary())));
+        assert!(data_declaration(".align #4096").is_ok());
+
+        assert!(data_declaration(".asciiz \"open").is_err());
+        assert!(data_declaration(".asciiz \"bad \\q\"").is_err());
+        assert!(data_declaration(".byte #1,").is_err());
+        assert!(data_declaration(".half").is_err());
+        assert!(data_declaration(".align #0").is_err());
+        assert!(data_declaration(".space #4").is_err());
+        assert!(data_declaration("load $1 #2").is_err());
+    }
+}
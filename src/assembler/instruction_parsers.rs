@@ -1,135 +1,767 @@
-use std::process;
-
-use crate::assembler::{Token, opcode_parsers::opcode_load, operand_parsers::integer_operand, register_parsers::register};
-use nom::{named, do_parse, types::CompleteStr, many1};
+use crate::assembler::{AssemblerError, AssemblerErrorKind, Position, SyntaxError, Token, opcode_parsers::opcode};
+use crate::assembler::directive_parsers::{data_declaration, section_directive, DataDeclaration};
+use crate::assembler::operand_parsers::{float_operand, integer_operand};
+use crate::assembler::register_parsers::{float_register, register};
+use crate::assembler::label_parsers::{label_declaration, label_usage};
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::symbols::{Section, Symbol, SymbolTable};
+use crate::image::Image;
+use crate::instruction::{Opcode, OperandKind, INSTRUCTION_LENGTH};
+use nom::{named, types::CompleteStr, alt, IResult};
 
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
+    label: Option<Token>,
     opcode: Token,
     operand1: Option<Token>,
     operand2: Option<Token>,
     operand3: Option<Token>,
+    /// Where the label, the opcode and each operand were written.
+    label_position: Position,
+    position: Position,
+    operand_positions: Vec<Position>,
+}
+
+fn operand(input: CompleteStr, kind: OperandKind) -> IResult<CompleteStr, Token> {
+    match kind {
+        OperandKind::Register => register(input),
+        OperandKind::Immediate16 | OperandKind::SignedImmediate16 | OperandKind::Immediate24 => immediate_operand(input),
+        OperandKind::FloatRegister => float_register(input),
+        OperandKind::Float64 => float_operand(input),
+    }
 }
 
 named!(
-    pub load_instruction<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        o: opcode_load >>
-        r: register >>
-        i: integer_operand >>
-        (
-            AssemblerInstruction {
-                opcode: o,
-                operand1: Some(r),
-                operand2: Some(i),
-                operand3: None
-            }
-        )
-    )
+    immediate_operand<CompleteStr, Token>,
+    alt!(integer_operand | label_usage)
 );
 
+/// What an operand of `kind` looks like, for syntax errors.
+fn describe(kind: OperandKind) -> &'static str {
+    match kind {
+        OperandKind::Register => "a register from `$0` to `$31`",
+        OperandKind::FloatRegister => "a float register from `$f0` to `$f31`",
+        OperandKind::Immediate16 | OperandKind::SignedImmediate16 | OperandKind::Immediate24 => {
+            "an immediate such as `#1` or a label such as `@loop`"
+        }
+        OperandKind::Float64 => "a float immediate such as `#1.5`",
+    }
+}
+
+/// Parses an optional label declaration and an opcode followed by exactly the
+/// operands its `OpcodeInfo` asks for. Positions are relative to `input`,
+/// taken as line 1.
+pub fn instruction(input: &str) -> Result<(&str, AssemblerInstruction), SyntaxError<'_>> {
+    let (rest, label) = match label_declaration(CompleteStr(input)) {
+        Ok((rest, label)) => (rest.0, Some(label)),
+        Err(_) => (input, None),
+    };
+    let position = Position::within(input, rest);
+    let Ok((CompleteStr(mut rest), Token::Op { code })) = opcode(CompleteStr(rest)) else {
+        return Err(SyntaxError { rest, expected: "an instruction".to_string() });
+    };
+
+    let mut operands = vec![];
+    let mut operand_positions = vec![];
+    for (index, kind) in code.info().operands.iter().enumerate() {
+        let Ok((remaining, parsed)) = operand(CompleteStr(rest), *kind) else {
+            let expected = format!("{} as operand {} of `{}`", describe(*kind), index + 1, code.info().mnemonic);
+            return Err(SyntaxError { rest, expected });
+        };
+        operands.push(parsed);
+        operand_positions.push(Position::within(input, rest));
+        rest = remaining.0;
+    }
+
+    let mut operands = operands.into_iter();
+    Ok((
+        rest,
+        AssemblerInstruction {
+            label,
+            opcode: Token::Op { code },
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+            label_position: Position::within(input, input),
+            position,
+            operand_positions,
+        }
+    ))
+}
+
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// A LOAD whose immediate does not fit in 16 bits is assembled as a LOAD of
+    /// the low half followed by a LOADHI of the high half.
+    fn wide_load(&self) -> Option<(u8, i32)> {
+        match (&self.opcode, &self.operand1, &self.operand2) {
+            (Token::Op { code: Opcode::LOAD }, Some(Token::Register { reg_number }), Some(Token::IntegerOperand { value }))
+                if !(0..=u16::MAX as i32).contains(value) => Some((*reg_number, *value)),
+            _ => None,
+        }
+    }
+
+    /// Moves the positions from the text the instruction was parsed from to
+    /// where that text sits in the source.
+    fn place(&mut self, line: usize, indent: usize) {
+        self.label_position = self.label_position.placed(line, indent);
+        self.position = self.position.placed(line, indent);
+        for position in &mut self.operand_positions {
+            *position = position.placed(line, indent);
+        }
+    }
+
+    /// Number of bytes the instruction assembles to.
+    pub fn size(&self) -> usize {
+        match (self.wide_load(), &self.opcode) {
+            (Some(_), _) => 2 * INSTRUCTION_LENGTH,
+            (None, Token::Op { code }) => code.info().length(),
+            (None, _) => INSTRUCTION_LENGTH,
+        }
+    }
+
+    /// Encodes the instruction placed at `offset`, resolving label operands
+    /// against `symbols`.
+    pub fn to_bytes(&self, offset: usize, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        if let Some((register, value)) = self.wide_load() {
+            let [high_1, high_2, low_1, low_2] = value.to_be_bytes();
+            return Ok(vec![
+                Opcode::LOAD as u8, register, low_1, low_2,
+                Opcode::LOADHI as u8, register, high_1, high_2,
+            ]);
+        }
+
         let mut results = vec![];
-        match self.opcode {
-            Token::Op{code} => match code {
-                _ => {
-                    results.push(code as u8);
-                    println!("Opcode found");
+        let Token::Op { code } = self.opcode else {
+            let kind = AssemblerErrorKind::InvalidSyntax { expected: "an opcode".to_string(), found: format!("`{}`", self.opcode) };
+            return Err(AssemblerError::new(self.position, kind));
+        };
+        results.push(code as u8);
+
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        for (index, (kind, operand)) in code.info().operands.iter().zip(operands).enumerate() {
+            let position = self.operand_positions.get(index).copied().unwrap_or(self.position);
+            let value = match (kind, operand) {
+                (OperandKind::Register, Some(Token::Register { reg_number }))
+                | (OperandKind::FloatRegister, Some(Token::FloatRegister { reg_number })) => {
+                    results.push(*reg_number);
+                    continue;
+                },
+                (OperandKind::Float64, Some(Token::FloatOperand { value })) => {
+                    results.extend_from_slice(&value.to_be_bytes());
+                    continue;
+                },
+                (_, Some(Token::IntegerOperand { value })) => *value,
+                (_, Some(Token::LabelUsage { name })) => {
+                    Self::resolve_label(code, *kind, name, offset, symbols).map_err(|kind| AssemblerError::new(position, kind))?
                 }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
+                (_, operand) => {
+                    let found = operand.as_ref().map_or("nothing".to_string(), |token| format!("`{token}`"));
+                    let kind = AssemblerErrorKind::InvalidSyntax { expected: describe(*kind).to_string(), found };
+                    return Err(AssemblerError::new(position, kind));
+                }
+            };
+            let (min, max) = match kind {
+                OperandKind::Immediate16 => (i16::MIN as i32, u16::MAX as i32),
+                OperandKind::SignedImmediate16 => (i16::MIN as i32, i16::MAX as i32),
+                _ => (0, (1 << 24) - 1),
+            };
+            if value < min || value > max {
+                return Err(AssemblerError::new(position, AssemblerErrorKind::ImmediateOutOfRange { value, opcode: code }));
             }
-        }
-
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            match operand {
-                Some(token) => match token {
-                    Token::Register { reg_number } => {
-                        results.push(*reg_number);
-                    },
-                    Token::IntegerOperand { value } => {
-                        let converted = *value as u16;
-                        let value1 = converted as u8;
-                        let value2 = (converted >> 8) as u8;
-                        results.push(value2);
-                        results.push(value1);
-                    },
-                    _ => {
-                        println!("Wrong code type found in operand field");
-                        std::process::exit(1);
-                    }
+            match kind {
+                OperandKind::Immediate16 | OperandKind::SignedImmediate16 => {
+                    let converted = value as u16;
+                    results.push((converted >> 8) as u8);
+                    results.push(converted as u8);
+                },
+                _ => {
+                    let converted = value as u32;
+                    results.push((converted >> 16) as u8);
+                    results.push((converted >> 8) as u8);
+                    results.push(converted as u8);
                 },
-                None => ()
             }
         }
-        results
+
+        results.resize(code.info().length(), 0);
+        Ok(results)
+    }
+
+    /// Turns a label into the value an operand of `kind` expects: the distance
+    /// from the end of the instruction for JMPF and JMPB, the absolute offset
+    /// otherwise. Jumps, calls and branches need a code label and LA a data
+    /// label; LOAD takes either.
+    fn resolve_label(code: Opcode, kind: OperandKind, name: &str, offset: usize, symbols: &SymbolTable) -> Result<i32, AssemblerErrorKind> {
+        let symbol = symbols
+            .symbol(name)
+            .ok_or_else(|| AssemblerErrorKind::UndefinedLabel { name: name.to_string() })?;
+        let expected = match code {
+            Opcode::LA => Some(Section::Data),
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::CALL
+            | Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE => Some(Section::Code),
+            _ => None,
+        };
+        if let Some(expected) = expected.filter(|expected| *expected != symbol.section) {
+            return Err(AssemblerErrorKind::WrongSection { name: name.to_string(), opcode: code, expected });
+        }
+        let target = symbol.offset as i64;
+        let next_instruction = (offset + code.info().length()) as i64;
+        let value = match code {
+            Opcode::JMPF => target - next_instruction,
+            Opcode::JMPB => next_instruction - target,
+            _ => target,
+        };
+        let limit = 1i64 << (8 * kind.width());
+        if value < 0 || value >= limit {
+            return Err(AssemblerErrorKind::LabelOutOfReach { name: name.to_string(), opcode: code });
+        }
+        Ok(value as i32)
     }
 }
 
+#[derive(Debug)]
 pub struct Program {
-    instructions: Vec<AssemblerInstruction>
+    instructions: Vec<AssemblerInstruction>,
+    /// The 1-based source line each instruction is written on, not its label's.
+    lines: Vec<usize>,
+    /// Everything declared in `.data` sections, in order.
+    data: Vec<DataDeclaration>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut program = vec![];
-
+    /// First pass: records the offset of every label declaration, in the
+    /// read-only data section for data labels and the code otherwise, with
+    /// the code starting at `code_base`. A label declared twice keeps its
+    /// first offset and is reported.
+    fn collect_symbols(&self, code_base: usize) -> (SymbolTable, Vec<AssemblerError>) {
+        let mut symbols = SymbolTable::new();
+        let mut errors = vec![];
+        let mut declare = |label: &Option<Token>, position: Position, offset: usize, section: Section| {
+            if let Some(Token::LabelDeclaration { name }) = label {
+                if symbols.has_symbol(name) {
+                    errors.push(AssemblerError::new(position, AssemblerErrorKind::DuplicateLabel { name: name.clone() }));
+                } else {
+                    symbols.add_symbol(Symbol::in_section(name, offset as u32, section));
+                }
+            }
+        };
+        let mut offset = 0;
+        for declaration in &self.data {
+            declare(&declaration.label, declaration.label_position, offset, Section::Data);
+            offset += declaration.size(offset);
+        }
+        let mut offset = code_base;
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+            declare(&instruction.label, instruction.label_position, offset, Section::Code);
+            offset += instruction.size();
+        }
+        (symbols, errors)
+    }
+
+    pub fn symbols(&self) -> Result<SymbolTable, Vec<AssemblerError>> {
+        match self.collect_symbols(0) {
+            (symbols, errors) if errors.is_empty() => Ok(symbols),
+            (_, errors) => Err(errors),
         }
+    }
+
+    /// Second pass: encodes every instruction with its labels resolved,
+    /// reporting every instruction that cannot be encoded.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        self.to_bytes_at(0)
+    }
+
+    /// Encodes the code to be placed `base` bytes into an existing program,
+    /// so labels resolve to where the instructions will end up.
+    pub fn to_bytes_at(&self, base: usize) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let (symbols, mut errors) = self.collect_symbols(base);
+        let program = self.encode_code(&symbols, base, &mut errors);
+        if errors.is_empty() { Ok(program) } else { Err(errors) }
+    }
 
+    /// Lays out the read-only data section with its labels resolved.
+    pub fn data_bytes(&self) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let (symbols, mut errors) = self.collect_symbols(0);
+        let data = self.encode_data(&symbols, &mut errors);
+        if errors.is_empty() { Ok(data) } else { Err(errors) }
+    }
+
+    fn encode_code(&self, symbols: &SymbolTable, base: usize, errors: &mut Vec<AssemblerError>) -> Vec<u8> {
+        let mut program = vec![];
+        for instruction in &self.instructions {
+            match instruction.to_bytes(base + program.len(), symbols) {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(error) => {
+                    errors.push(error);
+                    // keep later offsets right so their errors are still accurate
+                    program.resize(program.len() + instruction.size(), 0);
+                }
+            }
+        }
         program
     }
+
+    fn encode_data(&self, symbols: &SymbolTable, errors: &mut Vec<AssemblerError>) -> Vec<u8> {
+        let mut data = vec![];
+        for declaration in &self.data {
+            match declaration.to_bytes(data.len(), symbols) {
+                Ok(mut bytes) => data.append(&mut bytes),
+                Err(error) => {
+                    errors.push(error);
+                    data.resize(data.len() + declaration.size(data.len()), 0);
+                }
+            }
+        }
+        data
+    }
+
+    /// Maps every instruction's offset to the line it was written on.
+    pub fn source_map(&self) -> SourceMap {
+        let mut source_map = SourceMap::new();
+        let mut offset = 0;
+        for (instruction, line) in self.instructions.iter().zip(&self.lines) {
+            source_map.add_line(offset as u32, *line);
+            offset += instruction.size();
+        }
+        source_map
+    }
+
+    /// Assembles the program into an image whose entry point is the `main`
+    /// label when one is declared, and the first instruction otherwise.
+    /// Every problem in either section is reported, in source order.
+    pub fn to_image(&self) -> Result<Image, Vec<AssemblerError>> {
+        let (symbols, mut errors) = self.collect_symbols(0);
+        let ro_data = self.encode_data(&symbols, &mut errors);
+        let code = self.encode_code(&symbols, 0, &mut errors);
+        if !errors.is_empty() {
+            errors.sort_by_key(|error| error.position);
+            return Err(errors);
+        }
+        Ok(Image {
+            entry_point: symbols.code_offset("main").unwrap_or(0),
+            ro_data,
+            code,
+            symbols: if symbols.is_empty() { None } else { Some(symbols) },
+        })
+    }
 }
 
-named!(
-    pub program<CompleteStr, Program>,
-    do_parse!(
-        instructions: many1!(load_instruction) >>
-        (
-            Program {
-                instructions: instructions
+/// Cuts a comment off the end of `line`. Comments start at `;`, or at `#`
+/// when it begins the line or is followed by whitespace, so `#` immediates
+/// are left alone. Either character may appear in quoted strings and
+/// character literals.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return &line[..index],
+            None if c == '#' => {
+                let after = &line[index + 1..];
+                if line[..index].trim().is_empty() || after.is_empty() || after.starts_with(char::is_whitespace) {
+                    return &line[..index];
+                }
             }
-        )
-    )
-);
+            None => (),
+        }
+    }
+    line
+}
+
+/// What a label alone on its line must be followed by.
+fn after_label(label: &Token) -> String {
+    format!("an instruction or data directive after label `{}`", label.to_string().trim_end_matches(':'))
+}
+
+/// Runs `parser` over `line`, failing unless it consumes the whole line.
+fn whole_line<'a, T>(line: &'a str, parser: fn(&'a str) -> Result<(&'a str, T), SyntaxError<'a>>) -> Result<T, SyntaxError<'a>> {
+    let (rest, parsed) = parser(line)?;
+    if !rest.trim().is_empty() {
+        return Err(SyntaxError { rest, expected: "end of line".to_string() });
+    }
+    Ok(parsed)
+}
+
+/// Parses a whole source file, one instruction or directive per line, noting
+/// the line each instruction is written on. Blank lines and comments are skipped,
+/// and a label alone on a line labels whatever follows it. `.data` switches to
+/// parsing data directives until the next `.code`. Every line that cannot be
+/// parsed is reported, not just the first.
+pub fn program(source: &str) -> Result<Program, Vec<AssemblerError>> {
+    let mut instructions = vec![];
+    let mut lines = vec![];
+    let mut data = vec![];
+    let mut errors = vec![];
+    let mut section = Section::Code;
+    // a label alone on its line, waiting for the statement it labels
+    let mut pending: Option<(Token, Position)> = None;
+    let mut last_line = 1;
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        last_line = number;
+        let code = strip_comment(line).trim();
+        if code.is_empty() {
+            continue;
+        }
+        let indent = line[..line.len() - line.trim_start().len()].chars().count();
+        let error_at = |rest: &str, expected: String| {
+            let position = Position::within(code, rest).placed(number, indent);
+            AssemblerError::new(position, AssemblerErrorKind::syntax(expected, rest))
+        };
+
+        if let Ok((rest, label)) = label_declaration(CompleteStr(code)) {
+            if rest.is_empty() {
+                if let Some((previous, _)) = &pending {
+                    errors.push(error_at(code, after_label(previous)));
+                }
+                pending = Some((label, Position::within(code, code).placed(number, indent)));
+                continue;
+            }
+        }
+        let label = pending.take();
+        if let Ok((rest, switched)) = section_directive(CompleteStr(code)) {
+            if let Some((label, _)) = &label {
+                errors.push(error_at(code, after_label(label)));
+            } else if !rest.trim().is_empty() {
+                errors.push(error_at(&rest, "end of line".to_string()));
+            }
+            section = switched;
+        } else if section == Section::Data {
+            match whole_line(code, data_declaration) {
+                Ok(mut parsed) => {
+                    parsed.place(number, indent);
+                    if let Some((token, position)) = label {
+                        if parsed.label.is_some() {
+                            errors.push(error_at(code, after_label(&token)));
+                        }
+                        parsed.label = Some(token);
+                        parsed.label_position = position;
+                    }
+                    data.push(parsed);
+                }
+                Err(err) => errors.push(error_at(err.rest, err.expected)),
+            }
+        } else {
+            match whole_line(code, instruction) {
+                Ok(mut parsed) => {
+                    parsed.place(number, indent);
+                    if let Some((token, position)) = label {
+                        if parsed.label.is_some() {
+                            errors.push(error_at(code, after_label(&token)));
+                        }
+                        parsed.label = Some(token);
+                        parsed.label_position = position;
+                    }
+                    lines.push(number);
+                    instructions.push(parsed);
+                }
+                Err(err) => errors.push(error_at(err.rest, err.expected)),
+            }
+        }
+    }
+
+    if let Some((label, position)) = pending {
+        let kind = AssemblerErrorKind::InvalidSyntax { expected: after_label(&label), found: "end of file".to_string() };
+        errors.push(AssemblerError::new(position, kind));
+    }
+    if instructions.is_empty() && errors.is_empty() {
+        let kind = AssemblerErrorKind::InvalidSyntax { expected: "an instruction".to_string(), found: "end of file".to_string() };
+        errors.push(AssemblerError::new(Position { line: last_line, column: 1 }, kind));
+    }
+    if errors.is_empty() {
+        Ok(Program { instructions, lines, data })
+    } else {
+        Err(errors)
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::instruction::Opcode;
-
     use super::*;
 
+    fn kinds(errors: Vec<AssemblerError>) -> Vec<AssemblerErrorKind> {
+        errors.into_iter().map(|error| error.kind).collect()
+    }
 
     #[test]
     fn test_parse_load_instruction() {
-        let result = load_instruction(CompleteStr("load $1 #10"));
-        assert_eq!(result.is_ok(), true);
+        let result = instruction("load $1 #10");
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
-        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(rest, "");
         assert_eq!(token, 
             AssemblerInstruction {
+                label: None,
                 opcode: Token::Op { code: Opcode::LOAD },
                 operand1: Some(Token::Register { reg_number: 1 }),
                 operand2: Some(Token::IntegerOperand { value: 10 }),
-                operand3: None
+                operand3: None,
+                label_position: Position { line: 1, column: 1 },
+                position: Position { line: 1, column: 1 },
+                operand_positions: vec![Position { line: 1, column: 6 }, Position { line: 1, column: 9 }],
             }
         );
 
-        let result = load_instruction(CompleteStr("Load 1 10"));
-        assert_eq!(result.is_ok(), false);
+        let result = instruction("Load 1 10");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_operand_shapes() {
+        let (_, parsed) = instruction("ADD $1 $2 $3").unwrap();
+        assert_eq!(parsed.operand3, Some(Token::Register { reg_number: 3 }));
+
+        let (_, parsed) = instruction("jmp #12").unwrap();
+        assert_eq!(parsed.operand1, Some(Token::IntegerOperand { value: 12 }));
+        assert_eq!(parsed.operand2, None);
+
+        let (_, parsed) = instruction("hlt").unwrap();
+        assert_eq!(parsed.operand1, None);
+
+        assert!(instruction("add $1 $2").is_err());
+        assert!(instruction("jeq #4").is_err());
     }
     
     #[test]
     fn test_program_to_bytes() {
-        let result = program(CompleteStr("load $1 #100"));
-        assert_eq!(result.is_ok(), true);
-        let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
+        let program = program("load $1 #100").unwrap();
+        let bytecode = program.to_bytes().unwrap();
         assert_eq!(bytecode.len(), 4);
         println!("{:#?}", bytecode);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_program_to_bytes_pads_every_instruction() {
+        let program = program("load $0 #500\nadd $0 $0 $1\neq $0 $1\njeq $2\njmp #260\nhlt").unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![
+            1, 0, 1, 244,
+            2, 0, 0, 1,
+            9, 0, 1, 0,
+            11, 2, 0, 0,
+            6, 0, 1, 4,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_program_float_instructions() {
+        let program = program("loadf64 $f2 #1.5\nnext: jmpb @next\nmulf64 $f0 $f1 $f2").unwrap();
+        let mut expected = vec![29, 2];
+        expected.extend_from_slice(&1.5f64.to_be_bytes());
+        expected.extend_from_slice(&[0, 0, 8, 0, 0, 4, 32, 0, 1, 2]);
+        assert_eq!(program.to_bytes().unwrap(), expected);
+        assert_eq!(program.symbols().unwrap().symbol_value("next"), Some(12));
+
+        assert!(instruction("loadf64 $2 #1.5").is_err());
+        assert!(instruction("addf64 $f0 $f1 $2").is_err());
+    }
+
+    #[test]
+    fn test_program_bitwise_instructions() {
+        let parsed = program("and $1 $2 $3\nnot $1 $2\nsar $4 $5 $6\nmod $7 $8 $9\ngetrem $10").unwrap();
+        assert_eq!(parsed.to_bytes().unwrap(), vec![
+            40, 1, 2, 3,
+            43, 1, 2, 0,
+            46, 4, 5, 6,
+            47, 7, 8, 9,
+            54, 10, 0, 0,
+        ]);
+        assert!(program("not $1 $2 $3").is_err());
+    }
+
+    #[test]
+    fn test_program_compare_immediate_instructions() {
+        let parsed = program("lti $1 #-2\ngeqi $2 #32767\nsetcmp $3\nsetncmp $4").unwrap();
+        assert_eq!(parsed.to_bytes().unwrap(), vec![
+            58, 1, 0xFF, 0xFE,
+            59, 2, 0x7F, 0xFF,
+            61, 3, 0, 0,
+            62, 4, 0, 0,
+        ]);
+        let parsed = program("eqi $1 #32768").unwrap();
+        assert_eq!(parsed.to_bytes().map_err(kinds), Err(vec![AssemblerErrorKind::ImmediateOutOfRange { value: 32768, opcode: Opcode::EQI }]));
+    }
+
+    #[test]
+    fn test_program_branch_instructions() {
+        let parsed = program("loop: bne $1 $2 @loop\nbeq $1 $2 @end\nend: hlt").unwrap();
+        assert_eq!(parsed.to_bytes().unwrap(), vec![
+            64, 1, 2, 0, 0, 0, 0, 0,
+            63, 1, 2, 0, 0, 16, 0, 0,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_program_rejects_trailing_input() {
+        assert!(program("add $1 $2 $3 $4").is_err());
+    }
+
+    #[test]
+    fn test_parse_label_declaration_and_usage() {
+        let (_, parsed) = instruction("loop: jmp @loop").unwrap();
+        assert_eq!(parsed.label, Some(Token::LabelDeclaration { name: "loop".to_string() }));
+        assert_eq!(parsed.operand1, Some(Token::LabelUsage { name: "loop".to_string() }));
+
+        assert!(instruction("add $1 $2 @loop").is_err());
+    }
+
+    #[test]
+    fn test_program_resolves_labels() {
+        let source = "
+            start: load $0 @end
+            jmpf @end
+            middle: jmpb @start
+            jmp @middle
+            end: hlt
+        ";
+        let program = program(source).unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![
+            1, 0, 0, 16,
+            7, 0, 0, 8,
+            8, 0, 0, 12,
+            6, 0, 0, 8,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("load $1 #5 ; five"), "load $1 #5 ");
+        assert_eq!(strip_comment("load $1 #5 # five"), "load $1 #5 ");
+        assert_eq!(strip_comment("  # a whole line"), "  ");
+        assert_eq!(strip_comment("load $1 #5#"), "load $1 #5");
+        assert_eq!(strip_comment("load $1 #';' ; semicolon"), "load $1 #';' ");
+        assert_eq!(strip_comment("load $1 #'\\'' ; quote"), "load $1 #'\\'' ");
+        assert_eq!(strip_comment(".asciiz \"a; \\\"b # c\" ; text"), ".asciiz \"a; \\\"b # c\" ");
+    }
+
+    #[test]
+    fn test_program_comments_and_blank_lines() {
+        let source = "; counts to three\r\n\r\n# set up\r\nload $1 #3 ; limit\r\n\r\nloop:\r\n  add $0 $2 $0 # step\r\nhlt";
+        let parsed = program(source).unwrap();
+        assert_eq!(parsed.lines, vec![4, 7, 8]);
+        assert_eq!(parsed.instructions[1].label, Some(Token::LabelDeclaration { name: "loop".to_string() }));
+        assert_eq!(parsed.to_bytes().unwrap(), vec![1, 1, 0, 3, 2, 0, 2, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_program_one_statement_per_line() {
+        let failed_at = |source| match program(source) {
+            Err(errors) => errors.iter().map(|error| (error.position.line, error.position.column, error.kind.to_string())).collect::<Vec<_>>(),
+            Ok(_) => panic!("{source} should not parse"),
+        };
+        assert_eq!(failed_at("hlt\nload $1 #2 hlt ; two\nhlt"), vec![(2, 12, "expected end of line, found `hlt`".to_string())]);
+        assert_eq!(failed_at("one:\ntwo:\nhlt"), vec![(2, 1, "expected an instruction or data directive after label `one`, found `two:`".to_string())]);
+        assert_eq!(failed_at("hlt\nend: ; nothing follows"), vec![(2, 1, "expected an instruction or data directive after label `end`, found end of file".to_string())]);
+        assert_eq!(failed_at("start:\n.data\nhlt"), vec![
+            (2, 1, "expected an instruction or data directive after label `start`, found `.data`".to_string()),
+            (3, 1, "expected a data directive such as `.asciiz` or `.word`, found `hlt`".to_string()),
+        ]);
+        assert_eq!(failed_at("; only a comment\n"), vec![(1, 1, "expected an instruction, found end of file".to_string())]);
+    }
+
+    #[test]
+    fn test_program_collects_syntax_errors() {
+        let errors = program("load $1 #2\n  add $1 #5 $2\nfoo $1\n\tjmp\nhlt").unwrap_err();
+        let found: Vec<(Position, String)> = errors.into_iter().map(|error| (error.position, error.kind.to_string())).collect();
+        assert_eq!(found, vec![
+            (Position { line: 2, column: 10 }, "expected a register from `$0` to `$31` as operand 2 of `add`, found `#5`".to_string()),
+            (Position { line: 3, column: 1 }, "expected an instruction, found `foo`".to_string()),
+            (Position { line: 4, column: 5 }, "expected an immediate such as `#1` or a label such as `@loop` as operand 1 of `jmp`, found end of line".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_program_label_errors() {
+        let parsed = program("jmp @nowhere").unwrap();
+        assert_eq!(parsed.to_bytes().map_err(kinds), Err(vec![AssemblerErrorKind::UndefinedLabel { name: "nowhere".to_string() }]));
+
+        let parsed = program("twice: hlt\ntwice: hlt").unwrap();
+        assert_eq!(parsed.to_bytes().map_err(kinds), Err(vec![AssemblerErrorKind::DuplicateLabel { name: "twice".to_string() }]));
+
+        let parsed = program("back: jmpf @back").unwrap();
+        assert_eq!(parsed.to_bytes().map_err(kinds), Err(vec![AssemblerErrorKind::LabelOutOfReach { name: "back".to_string(), opcode: Opcode::JMPF }]));
+    }
+
+    #[test]
+    fn test_program_to_bytes_at() {
+        let parsed = program("here: load $1 @here\njmpb @here\nbeq $1 $2 @here").unwrap();
+        assert_eq!(parsed.to_bytes_at(8), Ok(vec![
+            1, 1, 0, 8,
+            8, 0, 0, 8,
+            63, 1, 2, 0, 0, 8, 0, 0,
+        ]));
+    }
+
+    #[test]
+    fn test_program_label_sections() {
+        let parsed = program(".data\nmsg: .asciiz \"hi\"\n.code\njmp @msg\nmain: la $1 @main\nload $2 @msg\nbeq $1 $2 @msg").unwrap();
+        let wrong = |name: &str, opcode, expected| AssemblerErrorKind::WrongSection { name: name.to_string(), opcode, expected };
+        assert_eq!(parsed.to_image().map_err(kinds).err(), Some(vec![
+            wrong("msg", Opcode::JMP, Section::Code),
+            wrong("main", Opcode::LA, Section::Data),
+            wrong("msg", Opcode::BEQ, Section::Code),
+        ]));
+        assert_eq!(
+            wrong("msg", Opcode::JMP, Section::Code).to_string(),
+            "JMP needs a code label, but `msg` is not in the code section"
+        );
+    }
+
+    #[test]
+    fn test_program_with_subroutine() {
+        let source = "
+            call @double
+            hlt
+            double: push $1
+            add $0 $0 $0
+            pop $1
+            ret
+        ";
+        let program = program(source).unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![
+            19, 0, 0, 8,
+            0, 0, 0, 0,
+            17, 1, 0, 0,
+            2, 0, 0, 0,
+            18, 1, 0, 0,
+            20, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_program_wide_load() {
+        let source = "
+            load $1 #-2
+            load $2 #0x12345
+            load $3 #65535
+            jmp @end
+            end: hlt
+        ";
+        let program = program(source).unwrap();
+        assert_eq!(program.to_bytes().unwrap(), vec![
+            1, 1, 255, 254,
+            28, 1, 255, 255,
+            1, 2, 0x23, 0x45,
+            28, 2, 0, 1,
+            1, 3, 255, 255,
+            6, 0, 0, 24,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_program_immediate_out_of_range() {
+        let parsed = program("loadhi $1 #65536").unwrap();
+        assert_eq!(parsed.to_bytes().map_err(kinds), Err(vec![AssemblerErrorKind::ImmediateOutOfRange { value: 65536, opcode: Opcode::LOADHI }]));
+
+        let parsed = program("jmp #-4").unwrap();
+        assert_eq!(parsed.to_bytes().map_err(kinds), Err(vec![AssemblerErrorKind::ImmediateOutOfRange { value: -4, opcode: Opcode::JMP }]));
+    }
+}
@@ -0,0 +1,70 @@
+use nom::types::CompleteStr;
+use nom::{named, ws, map, tag, take_while1, do_parse};
+use crate::assembler::Token;
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+named!(
+    pub label_declaration<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            name: take_while1!(is_label_char) >>
+            tag!(":") >>
+            (
+                Token::LabelDeclaration {
+                    name: name.to_string()
+                }
+            )
+        )
+    )
+);
+
+named!(
+    pub label_usage<CompleteStr, Token>,
+    ws!(
+        map!(
+            label_reference,
+            |name| Token::LabelUsage { name }
+        )
+    )
+);
+
+named!(
+    pub label_reference<CompleteStr, String>,
+    do_parse!(
+        tag!("@") >>
+        name: take_while1!(is_label_char) >>
+        (name.to_string())
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+        let result = label_declaration(CompleteStr("loop_1:"));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(token, Token::LabelDeclaration { name: "loop_1".to_string() });
+        assert_eq!(rest, CompleteStr(""));
+
+        let result = label_declaration(CompleteStr("loop"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let result = label_usage(CompleteStr("@loop"));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(token, Token::LabelUsage { name: "loop".to_string() });
+        assert_eq!(rest, CompleteStr(""));
+
+        let result = label_usage(CompleteStr("loop"));
+        assert!(result.is_err());
+    }
+}
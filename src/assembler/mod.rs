@@ -1,12 +1,269 @@
+use std::{error, fmt};
+
+use crate::image::Image;
 use crate::instruction::Opcode;
+use symbols::Section;
+use instruction_parsers::program;
+use source_map::SourceMap;
 pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod register_parsers;
+pub mod label_parsers;
+pub mod directive_parsers;
 pub mod instruction_parsers;
+pub mod symbols;
+pub mod source_map;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op {code: Opcode},
     Register {reg_number: u8},
-    IntegerOperand {value: i32}
-}
\ No newline at end of file
+    FloatRegister {reg_number: u8},
+    IntegerOperand {value: i32},
+    FloatOperand {value: f64},
+    LabelDeclaration {name: String},
+    LabelUsage {name: String},
+}
+
+/// Renders a token in the syntax the parsers accept.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Op { code } => write!(f, "{}", code.info().mnemonic),
+            Token::Register { reg_number } => write!(f, "${reg_number}"),
+            Token::FloatRegister { reg_number } => write!(f, "$f{reg_number}"),
+            Token::IntegerOperand { value } => write!(f, "#{value}"),
+            Token::FloatOperand { value } => write!(f, "#{value:?}"),
+            Token::LabelDeclaration { name } => write!(f, "{name}:"),
+            Token::LabelUsage { name } => write!(f, "@{name}"),
+        }
+    }
+}
+
+/// A 1-based line and column in the source. Columns count characters.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Where `rest`, a suffix of `text`, continues after any whitespace,
+    /// counting `text` as the start of line 1.
+    pub fn within(text: &str, rest: &str) -> Position {
+        let consumed = &text[..text.len() - rest.trim_start().len()];
+        Position { line: 1, column: consumed.chars().count() + 1 }
+    }
+
+    /// Moves a position taken within text that starts `indent` characters
+    /// into line `line`.
+    pub fn placed(self, line: usize, indent: usize) -> Position {
+        Position { line, column: self.column + indent }
+    }
+}
+
+/// Why a line failed to parse: the input left where parsing stopped and what
+/// would have been accepted there.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SyntaxError<'a> {
+    pub rest: &'a str,
+    pub expected: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AssemblerErrorKind {
+    /// `found` is the offending word in backticks, or `end of line` or
+    /// `end of file`.
+    InvalidSyntax { expected: String, found: String },
+    UndefinedLabel { name: String },
+    DuplicateLabel { name: String },
+    /// The label exists but the operand cannot encode the distance to it.
+    LabelOutOfReach { name: String, opcode: Opcode },
+    /// The label is declared in one section but the operand needs the other,
+    /// such as a jump to read-only data.
+    WrongSection { name: String, opcode: Opcode, expected: Section },
+    ImmediateOutOfRange { value: i32, opcode: Opcode },
+    /// A `.byte` or `.half` value does not fit in its width.
+    ValueOutOfRange { value: i32, directive: String },
+}
+
+impl AssemblerErrorKind {
+    /// A syntax error at the start of `rest`.
+    pub fn syntax(expected: impl Into<String>, rest: &str) -> AssemblerErrorKind {
+        let found = match rest.split_whitespace().next() {
+            Some(word) => format!("`{word}`"),
+            None => "end of line".to_string(),
+        };
+        AssemblerErrorKind::InvalidSyntax { expected: expected.into(), found }
+    }
+}
+
+impl fmt::Display for AssemblerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssemblerErrorKind::InvalidSyntax { expected, found } => write!(f, "expected {expected}, found {found}"),
+            AssemblerErrorKind::UndefinedLabel { name } => write!(f, "label `{name}` is not defined"),
+            AssemblerErrorKind::DuplicateLabel { name } => write!(f, "label `{name}` is defined more than once"),
+            AssemblerErrorKind::LabelOutOfReach { name, opcode } => {
+                write!(f, "label `{name}` cannot be reached by {opcode:?} from here")
+            }
+            AssemblerErrorKind::WrongSection { name, opcode, expected } => {
+                write!(f, "{opcode:?} needs a {expected} label, but `{name}` is not in the {expected} section")
+            }
+            AssemblerErrorKind::ImmediateOutOfRange { value, opcode } => {
+                write!(f, "immediate {value} does not fit in the operand of {opcode:?}")
+            }
+            AssemblerErrorKind::ValueOutOfRange { value, directive } => {
+                write!(f, "value {value} does not fit in .{directive}")
+            }
+        }
+    }
+}
+
+/// A problem found while assembling, located in the source. Renders as
+/// `file:line:column: message` followed by the offending line with a caret
+/// under the column.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssemblerError {
+    /// The file the source was read from, when known.
+    pub file: Option<String>,
+    pub position: Position,
+    pub kind: AssemblerErrorKind,
+    /// The text of the offending line, or empty when unknown.
+    pub source_line: String,
+}
+
+impl AssemblerError {
+    pub fn new(position: Position, kind: AssemblerErrorKind) -> AssemblerError {
+        AssemblerError { file: None, position, kind, source_line: String::new() }
+    }
+
+    /// Fills in the offending line from the whole `source`.
+    pub fn with_source(mut self, source: &str) -> AssemblerError {
+        self.source_line = source.lines().nth(self.position.line.saturating_sub(1)).unwrap_or("").to_string();
+        self
+    }
+
+    pub fn in_file(mut self, file: &str) -> AssemblerError {
+        self.file = Some(file.to_string());
+        self
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{}:{}: {}", self.position.line, self.position.column, self.kind)?;
+        if !self.source_line.is_empty() {
+            // keep tabs so the caret lines up however wide they are drawn
+            let indent: String = self
+                .source_line
+                .chars()
+                .take(self.position.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(f, "\n  {}\n  {indent}^", self.source_line)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for AssemblerError {}
+
+/// Assembles a whole source file into an executable image, or returns every
+/// problem found in it, in source order.
+pub fn assemble(source: &str) -> Result<Image, Vec<AssemblerError>> {
+    assemble_with_source_map(source).map(|(image, _)| image)
+}
+
+/// Assembles a whole source file, also returning the debug info that maps
+/// code offsets back to source lines.
+pub fn assemble_with_source_map(source: &str) -> Result<(Image, SourceMap), Vec<AssemblerError>> {
+    let with_source = |errors: Vec<AssemblerError>| -> Vec<AssemblerError> { errors.into_iter().map(|error| error.with_source(source)).collect() };
+    let parsed = program(source).map_err(with_source)?;
+    let image = parsed.to_image().map_err(with_source)?;
+    Ok((image, parsed.source_map()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_image() {
+        let image = assemble("load $0 #1\nmain: hlt").unwrap();
+        assert_eq!(image.entry_point, 4);
+        assert_eq!(image.code, vec![1, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(image.symbols.unwrap().symbol_value("main"), Some(4));
+
+        let image = assemble("hlt").unwrap();
+        assert_eq!(image.entry_point, 0);
+        assert_eq!(image.symbols, None);
+    }
+
+    #[test]
+    fn test_assemble_with_source_map() {
+        let (image, source_map) = assemble_with_source_map("load $0 #1\n\n  load $1 #100000\nmain:\nhlt\n").unwrap();
+        assert_eq!(image.code.len(), 16);
+        assert_eq!(source_map.line_at(0), Some(1));
+        assert_eq!(source_map.line_at(4), Some(3));
+        assert_eq!(source_map.line_at(8), Some(3));
+        assert_eq!(source_map.line_at(12), Some(5));
+    }
+
+    #[test]
+    fn test_assemble_data_section() {
+        let source = ".data\nhello: .asciiz \"hi\"\n.align #4\ntable: .half #1 #2\n.code\nmain: la $1 @table\n\
+                      .data\nlast: .byte #9\n.code\nloadr16 $2 $1\nhlt";
+        let (image, source_map) = assemble_with_source_map(source).unwrap();
+        assert_eq!(image.ro_data, vec![b'h', b'i', 0, 0, 0, 1, 0, 2, 9]);
+        assert_eq!(image.code, vec![69, 1, 0, 0, 4, 0, 0, 0, 71, 2, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(image.entry_point, 0);
+        let symbols = image.symbols.unwrap();
+        assert_eq!(symbols.symbol_value("last"), Some(8));
+        assert_eq!(symbols.code_offset("table"), None);
+        assert_eq!(source_map.line_at(8), Some(10));
+
+        let kinds = |source| assemble(source).map_err(|errors| errors.into_iter().map(|error| error.kind).collect::<Vec<_>>());
+        assert_eq!(kinds(".data\nx: .byte #1\n.code\nx: hlt"), Err(vec![AssemblerErrorKind::DuplicateLabel { name: "x".to_string() }]));
+        assert_eq!(
+            kinds(".data\nload $1 #2\n.code\nhlt"),
+            Err(vec![AssemblerErrorKind::syntax("a data directive such as `.asciiz` or `.word`", "load $1 #2")])
+        );
+        assert_eq!(
+            kinds(".byte #1\nhlt"),
+            Err(vec![AssemblerErrorKind::syntax("an instruction", ".byte #1")])
+        );
+    }
+
+    #[test]
+    fn test_assemble_reports_every_error() {
+        let errors = assemble("load $0 #1\n\tadd $1 #5 $2\nhlt").unwrap_err();
+        assert_eq!(
+            errors[0].clone().in_file("prog.asm").to_string(),
+            "prog.asm:2:9: expected a register from `$0` to `$31` as operand 2 of `add`, found `#5`\n  \tadd $1 #5 $2\n  \t       ^"
+        );
+
+        let errors = assemble("jmp @nowhere\nload $1 @away\nhlt").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].to_string(), "1:5: label `nowhere` is not defined\n  jmp @nowhere\n      ^");
+        assert_eq!(errors[1].position, Position { line: 2, column: 9 });
+    }
+
+    #[test]
+    fn test_assemble_rejects_missing_registers() {
+        let errors = assemble("load $300 #1\nload $40 #1\naddf64 $f0 $f1 $f32\nhlt").unwrap_err();
+        let found: Vec<(usize, usize, String)> = errors
+            .iter()
+            .map(|error| (error.position.line, error.position.column, error.kind.to_string()))
+            .collect();
+        assert_eq!(found, vec![
+            (1, 6, "expected a register from `$0` to `$31` as operand 1 of `load`, found `$300`".to_string()),
+            (2, 6, "expected a register from `$0` to `$31` as operand 1 of `load`, found `$40`".to_string()),
+            (3, 16, "expected a float register from `$f0` to `$f31` as operand 3 of `addf64`, found `$f32`".to_string()),
+        ]);
+        assert!(errors[0].to_string().ends_with("\n  load $300 #1\n       ^"));
+    }
+}
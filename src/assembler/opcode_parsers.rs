@@ -1,12 +1,15 @@
 use nom::types::CompleteStr;
-use nom::{named, do_parse, tag};
+use nom::{named, ws, map_opt, alphanumeric1};
 use crate::assembler::Token;
 use crate::instruction::Opcode;
 
 named!(
-    pub opcode_load<CompleteStr, Token>,
-    do_parse!(
-        tag!("load") >> (Token::Op{code: Opcode::LOAD})
+    pub opcode<CompleteStr, Token>,
+    ws!(
+        map_opt!(
+            alphanumeric1,
+            |mnemonic: CompleteStr| Opcode::from_mnemonic(&mnemonic).map(|code| Token::Op { code })
+        )
     )
 );
 
@@ -16,13 +19,29 @@ mod tests {
 
     #[test]
     fn test_parse_load_opcode() {
-        let result = opcode_load(CompleteStr("load"));
-        assert_eq!(result.is_ok(), true);
+        let result = opcode(CompleteStr("load"));
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op{ code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
 
-        let result = opcode_load(CompleteStr("aloa"));
-        assert_eq!(result.is_ok(), false);
+        let result = opcode(CompleteStr("aloa"));
+        assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_opcode_ignores_case() {
+        let (_, token) = opcode(CompleteStr("JMPF")).unwrap();
+        assert_eq!(token, Token::Op{ code: Opcode::JMPF });
+        let (_, token) = opcode(CompleteStr("Div")).unwrap();
+        assert_eq!(token, Token::Op{ code: Opcode::DIV });
+        let (_, token) = opcode(CompleteStr("StoreM16")).unwrap();
+        assert_eq!(token, Token::Op{ code: Opcode::STOREM16 });
+    }
+
+    #[test]
+    fn test_parse_opcode_needs_whole_word() {
+        let result = opcode(CompleteStr("loads"));
+        assert!(result.is_err());
+    }
+}
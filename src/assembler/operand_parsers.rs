@@ -1,35 +1,189 @@
 use nom::types::CompleteStr;
-use nom::{named, digit, ws, tag};
+use nom::{named, ws, map, Context, Err, ErrorKind, IResult};
 use crate::assembler::Token;
 
 named!(
     pub integer_operand<CompleteStr, Token>,
     ws!(
-        do_parse!(
-            tag!("#") >>
-            number: digit >>
-            (
-                Token::IntegerOperand {
-                    value: number.parse::<i32>().unwrap()
-                }
-            )
+        map!(
+            integer_literal,
+            |value| Token::IntegerOperand { value }
         )
     )
 );
 
+named!(
+    pub float_operand<CompleteStr, Token>,
+    ws!(
+        map!(
+            float_literal,
+            |value| Token::FloatOperand { value }
+        )
+    )
+);
+
+/// Parses `#` directly followed by a decimal number with an optional fraction
+/// and exponent, such as `#2`, `#-0.5` or `#1.5e-3`, or by `inf` or `nan`.
+fn float_literal(input: CompleteStr) -> IResult<CompleteStr, f64> {
+    let error = |kind| Err(Err::Error(Context::Code(input, kind)));
+    let Some(literal) = input.strip_prefix('#') else {
+        return error(ErrorKind::Tag);
+    };
+    let unsigned = literal.strip_prefix('-').unwrap_or(literal);
+    for special in ["inf", "nan"] {
+        if unsigned.get(..special.len()).is_some_and(|word| word.eq_ignore_ascii_case(special)) {
+            let length = literal.len() - unsigned.len() + special.len();
+            let value = literal[..length].to_ascii_lowercase().parse::<f64>().unwrap();
+            return Ok((CompleteStr(&literal[length..]), value));
+        }
+    }
+
+    let digits = |text: &str| text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let mut length = literal.len() - unsigned.len();
+    let whole = digits(&literal[length..]);
+    if whole == 0 {
+        return error(ErrorKind::Digit);
+    }
+    length += whole;
+    if literal[length..].starts_with('.') {
+        length += 1 + digits(&literal[length + 1..]);
+    }
+    if let Some(exponent) = literal[length..].strip_prefix(['e', 'E']) {
+        let sign = usize::from(exponent.starts_with(['+', '-']));
+        let exponent_digits = digits(&exponent[sign..]);
+        if exponent_digits > 0 {
+            length += 1 + sign + exponent_digits;
+        }
+    }
+    match literal[..length].parse::<f64>() {
+        Ok(value) => Ok((CompleteStr(&literal[length..]), value)),
+        Err(_) => error(ErrorKind::Digit),
+    }
+}
+
+/// Parses `#` directly followed by a decimal, `0x` hexadecimal, `0b` binary or
+/// quoted character literal. Any value that fits in 32 bits, signed or
+/// unsigned, is accepted and kept as its `i32` bit pattern.
+pub fn integer_literal(input: CompleteStr) -> IResult<CompleteStr, i32> {
+    let error = |kind| Err(Err::Error(Context::Code(input, kind)));
+    let Some(literal) = input.strip_prefix('#') else {
+        return error(ErrorKind::Tag);
+    };
+
+    if let Some(quoted) = literal.strip_prefix('\'') {
+        let mut chars = quoted.char_indices();
+        let value = match chars.next() {
+            Some((_, '\\')) => match chars.next() {
+                Some((_, 'n')) => '\n',
+                Some((_, 't')) => '\t',
+                Some((_, 'r')) => '\r',
+                Some((_, '0')) => '\0',
+                Some((_, c)) if c == '\\' || c == '\'' => c,
+                _ => return error(ErrorKind::Char),
+            },
+            Some((_, '\'')) | None => return error(ErrorKind::Char),
+            Some((_, c)) => c,
+        };
+        return match chars.next() {
+            Some((end, '\'')) => Ok((CompleteStr(&quoted[end + 1..]), value as i32)),
+            _ => error(ErrorKind::Char),
+        };
+    }
+
+    let (negative, unsigned) = match literal.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, literal),
+    };
+    let (radix, digits) = if let Some(digits) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+        (16, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0b").or_else(|| unsigned.strip_prefix("0B")) {
+        (2, digits)
+    } else {
+        (10, unsigned)
+    };
+    let length = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+    let Ok(magnitude) = i64::from_str_radix(&digits[..length], radix) else {
+        return error(ErrorKind::Digit);
+    };
+    let value = if negative { -magnitude } else { magnitude };
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return error(ErrorKind::Digit);
+    }
+    Ok((CompleteStr(&digits[length..]), value as u32 as i32))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parsed(input: &str) -> Option<i32> {
+        match integer_operand(CompleteStr(input)) {
+            Ok((rest, Token::IntegerOperand { value })) if rest.is_empty() => Some(value),
+            _ => None,
+        }
+    }
+
     #[test]
     fn test_parse_integer_operand() {
         let result = integer_operand(CompleteStr("#0"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, value) = result.unwrap();
         assert_eq!(value, Token::IntegerOperand { value: 0 });
         assert_eq!(rest, CompleteStr(""));
 
         let result = integer_operand(CompleteStr("0"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_integer_literal_forms() {
+        assert_eq!(parsed("#-5"), Some(-5));
+        assert_eq!(parsed("#0xFF"), Some(255));
+        assert_eq!(parsed("#-0x10"), Some(-16));
+        assert_eq!(parsed("#0b1010"), Some(10));
+        assert_eq!(parsed("#'a'"), Some(97));
+        assert_eq!(parsed("#'\\n'"), Some(10));
+        assert_eq!(parsed("#' '"), Some(32));
+        assert_eq!(parsed("#2147483647"), Some(i32::MAX));
+        assert_eq!(parsed("#-2147483648"), Some(i32::MIN));
+        assert_eq!(parsed("#0xFFFFFFFF"), Some(-1));
+    }
+
+    fn parsed_float(input: &str) -> Option<f64> {
+        match float_operand(CompleteStr(input)) {
+            Ok((rest, Token::FloatOperand { value })) if rest.is_empty() => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_float_literals() {
+        assert_eq!(parsed_float("#2"), Some(2.0));
+        assert_eq!(parsed_float("#-0.5"), Some(-0.5));
+        assert_eq!(parsed_float("#3."), Some(3.0));
+        assert_eq!(parsed_float("#1.5e-3"), Some(0.0015));
+        assert_eq!(parsed_float("#1E300"), Some(1e300));
+        assert_eq!(parsed_float("#-inf"), Some(f64::NEG_INFINITY));
+        assert!(parsed_float("#NaN").unwrap().is_nan());
+        assert_eq!(parsed_float("#.5"), None);
+        assert_eq!(parsed_float("# 1.0"), None);
+        assert_eq!(parsed_float("#1.0x"), None);
+        for value in [0.1, -2.0, 1e-7, 6.02e23, f64::MAX, f64::INFINITY] {
+            let text = Token::FloatOperand { value }.to_string();
+            assert_eq!(parsed_float(&text), Some(value), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_parse_integer_literal_errors() {
+        assert_eq!(parsed("#"), None);
+        assert_eq!(parsed("#-"), None);
+        assert_eq!(parsed("#0x"), None);
+        assert_eq!(parsed("#0b2"), None);
+        assert_eq!(parsed("#''"), None);
+        assert_eq!(parsed("#'ab'"), None);
+        assert_eq!(parsed("#4294967296"), None);
+        assert_eq!(parsed("#-2147483649"), None);
+        assert_eq!(parsed("# 5"), None);
     }
-}
\ No newline at end of file
+}
@@ -1,16 +1,39 @@
 use nom::types::CompleteStr;
-use nom::{named, digit, ws, tag};
+use nom::{named, digit, ws, tag, map_opt};
 use crate::assembler::Token;
 
+/// Registers in each bank, numbered from zero.
+pub const REGISTER_COUNT: u8 = 32;
+
+/// The register numbered by `digits`, if the bank has one.
+fn register_number(digits: CompleteStr) -> Option<u8> {
+    digits.parse::<u8>().ok().filter(|number| *number < REGISTER_COUNT)
+}
+
 named!(
     pub register<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            register_number: digit >>
+            register_number: map_opt!(digit, register_number) >>
             (
                 Token::Register {
-                    reg_number: register_number.parse::<u8>().unwrap()
+                    reg_number: register_number
+                }
+            )
+        )
+    )
+);
+
+named!(
+    pub float_register<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("$f") >>
+            register_number: map_opt!(digit, register_number) >>
+            (
+                Token::FloatRegister {
+                    reg_number: register_number
                 }
             )
         )
@@ -24,8 +47,23 @@ mod tests {
     #[test]
     fn test_parse_registers() {
         let result = register(CompleteStr("$0"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let result = register(CompleteStr("$"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+        let result = register(CompleteStr("$f1"));
+        assert!(result.is_err());
+        assert_eq!(register(CompleteStr("$31")), Ok((CompleteStr(""), Token::Register { reg_number: 31 })));
+        assert!(register(CompleteStr("$32")).is_err());
+        assert!(register(CompleteStr("$300")).is_err());
+    }
+
+    #[test]
+    fn test_parse_float_registers() {
+        let result = float_register(CompleteStr("$f12"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::FloatRegister { reg_number: 12 })));
+        let result = float_register(CompleteStr("$1"));
+        assert!(result.is_err());
+        assert!(float_register(CompleteStr("$f40")).is_err());
+        assert!(float_register(CompleteStr("$f300")).is_err());
     }
 }
\ No newline at end of file
@@ -0,0 +1,43 @@
+/// Debug info mapping offsets in the assembled program back to the source
+/// lines that produced them.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SourceMap {
+    /// The start offset and 1-based line of every instruction, in offset order.
+    lines: Vec<(u32, usize)>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { lines: vec![] }
+    }
+
+    /// Records that the instruction starting at `offset` came from `line`.
+    /// Offsets must be added in increasing order.
+    pub fn add_line(&mut self, offset: u32, line: usize) {
+        self.lines.push((offset, line));
+    }
+
+    /// The line of the instruction covering `offset`, which may lie inside an
+    /// instruction that assembles to more than one machine instruction.
+    pub fn line_at(&self, offset: u32) -> Option<usize> {
+        let index = self.lines.partition_point(|(start, _)| *start <= offset);
+        index.checked_sub(1).map(|index| self.lines[index].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_at() {
+        let mut lines = SourceMap::new();
+        lines.add_line(0, 1);
+        lines.add_line(4, 3);
+        lines.add_line(12, 4);
+        assert_eq!(lines.line_at(0), Some(1));
+        assert_eq!(lines.line_at(8), Some(3));
+        assert_eq!(lines.line_at(100), Some(4));
+        assert_eq!(SourceMap::new().line_at(0), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+
+/// The part of an image a symbol's offset points into.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Section {
+    #[default]
+    Code,
+    /// The read-only data section.
+    Data,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Code => write!(f, "code"),
+            Section::Data => write!(f, "data"),
+        }
+    }
+}
+
+/// A named offset into the assembled program.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u32,
+    pub section: Section,
+}
+
+impl Symbol {
+    /// A symbol in the code section.
+    pub fn new(name: &str, offset: u32) -> Symbol {
+        Symbol::in_section(name, offset, Section::Code)
+    }
+
+    pub fn in_section(name: &str, offset: u32, section: Section) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            offset,
+            section,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: vec![] }
+    }
+
+    pub fn add_symbol(&mut self, symbol: Symbol) {
+        self.symbols.push(symbol);
+    }
+
+    pub fn has_symbol(&self, name: &str) -> bool {
+        self.symbols.iter().any(|symbol| symbol.name == name)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The offset of `name` in whichever section it was declared in.
+    pub fn symbol_value(&self, name: &str) -> Option<u32> {
+        self.symbol(name).map(|symbol| symbol.offset)
+    }
+
+    /// The offset of `name` when it labels code.
+    pub fn code_offset(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name && symbol.section == Section::Code)
+            .map(|symbol| symbol.offset)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("start", 12));
+        assert!(symbols.has_symbol("start"));
+        assert_eq!(symbols.symbol_value("start"), Some(12));
+        assert_eq!(symbols.symbol_value("end"), None);
+
+        symbols.add_symbol(Symbol::in_section("message", 0, Section::Data));
+        assert_eq!(symbols.symbol_value("message"), Some(0));
+        assert_eq!(symbols.code_offset("message"), None);
+        assert_eq!(symbols.code_offset("start"), Some(12));
+    }
+}
@@ -0,0 +1,414 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+use crate::{assembler, disassembler, image::Image, repl::REPL, verifier};
+use crate::vm::{OverflowMode, StepOutcome, VM};
+use crate::assembler::{source_map::SourceMap, AssemblerError};
+use crate::tracer::{TraceFormat, Tracer};
+
+const USAGE: &str = "\
+usage: porul [command]
+
+commands:
+  run <file> [--exit-register <n>]  verify and run an image or assembly source; the exit
+                                    code is the value of register $n ($0 by default),
+                                    which must be 0 to 249
+      [--trace <log>]               log every executed instruction to <log>
+      [--trace-format text|json]    write the log as text (the default) or JSON Lines
+      [--profile]                   print instruction counts and hot spots afterwards,
+                                    with source lines when running assembly source
+      [--fuel <n>]                  stop with an error once <n> units of fuel are spent,
+                                    and print the fuel used
+      [--overflow trap|wrap|saturate]
+                                    what ADD, SUB and MUL do on overflow (wrap by default)
+  verify <file>                     report problems the verifier finds without running
+  asm <file> [-o <output>]          assemble source into an image (<file>.prl by default)
+  disasm <file> [--source]          list the instructions of an image or source file;
+                                    --source prints text that assembles back to the same
+                                    bytes, and fails on bytes no source can produce
+  repl                              start the interactive REPL (the default)
+  help                              print this message
+
+exit codes: run exits with the program's result, 0 to 249; otherwise 0 on success,
+250 when assembling, loading or running fails, including results outside 0 to 249,
+and 251 for usage errors";
+
+/// Largest result a program can exit with. The codes above it belong to porul,
+/// so a script can tell the program's answer from porul failing.
+pub const MAX_EXIT_CODE: i32 = 249;
+/// Exit code for failures while assembling, loading or running a program.
+pub const EXIT_FAILURE: i32 = 250;
+/// Exit code for malformed command lines.
+pub const EXIT_USAGE: i32 = 251;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run { path: PathBuf, exit_register: usize, trace: Option<(PathBuf, TraceFormat)>, profile: bool, fuel: Option<u64>, overflow: OverflowMode },
+    Asm { input: PathBuf, output: PathBuf },
+    Disasm { path: PathBuf, source: bool },
+    Verify { path: PathBuf },
+    Repl,
+    Help,
+}
+
+/// Parses the command line, without the program name.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        None | Some("repl") => Command::Repl,
+        Some("help") | Some("-h") | Some("--help") => Command::Help,
+        Some("run") => {
+            let mut path = None;
+            let mut exit_register = 0;
+            let mut trace = None;
+            let mut trace_format = TraceFormat::Text;
+            let mut profile = false;
+            let mut fuel = None;
+            let mut overflow = OverflowMode::default();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-r" | "--exit-register" => {
+                        let value = args.next().ok_or(format!("{arg} needs a register number"))?;
+                        exit_register = value
+                            .trim_start_matches('$')
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|register| *register < 32)
+                            .ok_or(format!("`{value}` is not a register between 0 and 31"))?;
+                    }
+                    "--trace" => {
+                        trace = Some(PathBuf::from(args.next().ok_or(format!("{arg} needs a file"))?));
+                    }
+                    "--trace-format" => {
+                        let value = args.next().ok_or(format!("{arg} needs `text` or `json`"))?;
+                        trace_format = TraceFormat::from_name(value)
+                            .ok_or(format!("`{value}` is not a trace format; use `text` or `json`"))?;
+                    }
+                    "--profile" => profile = true,
+                    "--fuel" => {
+                        let value = args.next().ok_or(format!("{arg} needs an amount"))?;
+                        fuel = Some(value.parse::<u64>().map_err(|_| format!("`{value}` is not an amount of fuel"))?);
+                    }
+                    "--overflow" => {
+                        let value = args.next().ok_or(format!("{arg} needs `trap`, `wrap` or `saturate`"))?;
+                        overflow = OverflowMode::from_name(value)
+                            .ok_or(format!("`{value}` is not an overflow mode; use `trap`, `wrap` or `saturate`"))?;
+                    }
+                    _ => path = Some(positional(arg, path)?),
+                }
+            }
+            let trace = trace.map(|log| (log, trace_format));
+            Command::Run { path: path.ok_or("run needs a file")?, exit_register, trace, profile, fuel, overflow }
+        }
+        Some("asm") => {
+            let mut input = None;
+            let mut output = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" | "--output" => {
+                        output = Some(PathBuf::from(args.next().ok_or(format!("{arg} needs a file"))?));
+                    }
+                    _ => input = Some(positional(arg, input)?),
+                }
+            }
+            let input = input.ok_or("asm needs a file")?;
+            let output = output.unwrap_or_else(|| input.with_extension("prl"));
+            Command::Asm { input, output }
+        }
+        Some("disasm") => {
+            let mut path = None;
+            let mut source = false;
+            for arg in args {
+                match arg.as_str() {
+                    "-s" | "--source" => source = true,
+                    _ => path = Some(positional(arg, path)?),
+                }
+            }
+            Command::Disasm { path: path.ok_or("disasm needs a file")?, source }
+        }
+        Some("verify") => {
+            let mut path = None;
+            for arg in args {
+                path = Some(positional(arg, path)?);
+            }
+            Command::Verify { path: path.ok_or("verify needs a file")? }
+        }
+        Some(other) => return Err(format!("unknown command `{other}`")),
+    };
+    Ok(command)
+}
+
+fn positional(arg: &str, previous: Option<PathBuf>) -> Result<PathBuf, String> {
+    if arg.starts_with('-') {
+        return Err(format!("unknown option `{arg}`"));
+    }
+    if previous.is_some() {
+        return Err(format!("unexpected argument `{arg}`"));
+    }
+    Ok(PathBuf::from(arg))
+}
+
+/// Runs the command line and returns the process exit code.
+pub fn main(args: &[String]) -> i32 {
+    let command = match parse_args(args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("porul: {err}\n\n{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+
+    let result = match command {
+        Command::Run { path, exit_register, trace, profile, fuel, overflow } => {
+            run(&path, exit_register, trace.as_ref(), profile, fuel, overflow)
+                .and_then(|value| exit_code(&path, exit_register, value))
+        }
+        Command::Asm { input, output } => asm(&input, &output).map(|_| 0),
+        Command::Disasm { path, source } => disasm(&path, source).map(|listing| {
+            print!("{listing}");
+            0
+        }),
+        Command::Verify { path } => load_image(&path).and_then(|image| verify(&path, &image)).map(|_| 0),
+        Command::Repl => {
+            REPL::new().run();
+            Ok(0)
+        }
+        Command::Help => {
+            println!("{USAGE}");
+            Ok(0)
+        }
+    };
+
+    result.unwrap_or_else(|err| {
+        eprintln!("porul: {err}");
+        EXIT_FAILURE
+    })
+}
+
+/// Checks that a program's result can be told apart from porul's own exit codes.
+fn exit_code(path: &Path, exit_register: usize, value: i32) -> Result<i32, String> {
+    if !(0..=MAX_EXIT_CODE).contains(&value) {
+        return Err(format!(
+            "{}: ${exit_register} holds {value}, outside the exit code range 0 to {MAX_EXIT_CODE}",
+            path.display()
+        ));
+    }
+    Ok(value)
+}
+
+/// Reads an image, assembling the file first when it is source code.
+pub fn load_image(path: &Path) -> Result<Image, String> {
+    let bytes = fs::read(path).map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+    if Image::is_image(&bytes) {
+        return Image::from_bytes(&bytes).map_err(|err| format!("{}: {err}", path.display()));
+    }
+    let source = String::from_utf8(bytes)
+        .map_err(|_| format!("{}: neither an image nor UTF-8 source", path.display()))?;
+    assembler::assemble(&source).map_err(|errors| report(path, errors))
+}
+
+/// Renders every assembler error, each prefixed with the file it came from.
+fn report(path: &Path, errors: Vec<AssemblerError>) -> String {
+    let file = path.display().to_string();
+    let lines: Vec<String> = errors.into_iter().map(|error| error.in_file(&file).to_string()).collect();
+    lines.join("\n")
+}
+
+/// Fails with every diagnostic when the verifier rejects the image's code.
+fn verify(path: &Path, image: &Image) -> Result<(), String> {
+    let diagnostics = verifier::verify(&image.code);
+    if diagnostics.is_empty() {
+        return Ok(());
+    }
+    let lines: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+    Err(format!("{} failed verification:\n{}", path.display(), lines.join("\n")))
+}
+
+fn run(
+    path: &Path,
+    exit_register: usize,
+    trace: Option<&(PathBuf, TraceFormat)>,
+    profile: bool,
+    fuel: Option<u64>,
+    overflow: OverflowMode,
+) -> Result<i32, String> {
+    let image = load_image(path)?;
+    verify(path, &image)?;
+    let mut vm = VM::new();
+    vm.load(&image.to_bytes()).map_err(|err| format!("{}: {err}", path.display()))?;
+    if profile {
+        vm.enable_profiling();
+    }
+    vm.set_fuel(fuel);
+    vm.set_overflow_mode(overflow);
+    let result = match trace {
+        Some((log, format)) => {
+            let file = fs::File::create(log).map_err(|err| format!("cannot write {}: {err}", log.display()))?;
+            Tracer::new(io::BufWriter::new(file), *format).run(&mut vm).map_err(|err| err.to_string())
+        }
+        None => vm.run().map_err(|err| err.to_string()),
+    };
+    if let Some(profiler) = vm.profiler() {
+        let debug_info = source_map(path);
+        let source = debug_info.as_ref().map(|(text, source_map)| (source_map, text.as_str()));
+        print!("{}", profiler.report(&image.code, source));
+    }
+    let outcome = result.map_err(|err| format!("{}: runtime error: {err}", path.display()))?;
+    if let Some(budget) = fuel {
+        if outcome == StepOutcome::OutOfFuel {
+            return Err(format!("{}: ran out of fuel after using {} units", path.display(), vm.fuel_used()));
+        }
+        println!("fuel used: {} of {budget}", vm.fuel_used());
+    }
+    Ok(vm.registers[exit_register])
+}
+
+/// The source text and its line mapping, when `path` is assembly source
+/// rather than an image.
+fn source_map(path: &Path) -> Option<(String, SourceMap)> {
+    let text = fs::read_to_string(path).ok()?;
+    let (_, source_map) = assembler::assemble_with_source_map(&text).ok()?;
+    Some((text, source_map))
+}
+
+fn asm(input: &Path, output: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(input).map_err(|err| format!("cannot read {}: {err}", input.display()))?;
+    let image = assembler::assemble(&source).map_err(|errors| report(input, errors))?;
+    fs::write(output, image.to_bytes()).map_err(|err| format!("cannot write {}: {err}", output.display()))
+}
+
+fn disasm(path: &Path, source: bool) -> Result<String, String> {
+    let image = load_image(path)?;
+    if source {
+        let symbols = image.symbols.as_ref();
+        let code = disassembler::source(&image.code, symbols).map_err(|err| format!("{}: {err}", path.display()))?;
+        return Ok(disassembler::data_source(&image.ro_data, symbols) + &code);
+    }
+    let mut listing = format!(
+        "entry point: {}\nread-only data: {} bytes\ncode: {} bytes\n\n",
+        image.entry_point,
+        image.ro_data.len(),
+        image.code.len()
+    );
+    listing += &disassembler::listing(&image.code, image.symbols.as_ref());
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args("")), Ok(Command::Repl));
+        assert_eq!(parse_args(&args("run prog.prl")), Ok(Command::Run { path: "prog.prl".into(), exit_register: 0, trace: None, profile: false, fuel: None, overflow: OverflowMode::Wrap }));
+        assert_eq!(parse_args(&args("run -r $3 prog.asm --profile --fuel 50")), Ok(Command::Run { path: "prog.asm".into(), exit_register: 3, trace: None, profile: true, fuel: Some(50), overflow: OverflowMode::Wrap }));
+        assert_eq!(
+            parse_args(&args("run prog.asm --trace out.log --trace-format json")),
+            Ok(Command::Run { path: "prog.asm".into(), exit_register: 0, trace: Some(("out.log".into(), TraceFormat::JsonLines)), profile: false, fuel: None, overflow: OverflowMode::Wrap })
+        );
+        assert_eq!(parse_args(&args("asm prog.asm")), Ok(Command::Asm { input: "prog.asm".into(), output: "prog.prl".into() }));
+        assert_eq!(parse_args(&args("asm prog.asm -o out.bin")), Ok(Command::Asm { input: "prog.asm".into(), output: "out.bin".into() }));
+        assert_eq!(parse_args(&args("disasm prog.prl")), Ok(Command::Disasm { path: "prog.prl".into(), source: false }));
+        assert_eq!(parse_args(&args("disasm --source prog.prl")), Ok(Command::Disasm { path: "prog.prl".into(), source: true }));
+        assert_eq!(parse_args(&args("verify prog.prl")), Ok(Command::Verify { path: "prog.prl".into() }));
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(&args("launch")).is_err());
+        assert!(parse_args(&args("run")).is_err());
+        assert!(parse_args(&args("run a b")).is_err());
+        assert!(parse_args(&args("run -r 32 a")).is_err());
+        assert!(parse_args(&args("asm a --verbose")).is_err());
+        assert!(parse_args(&args("run a --trace-format xml")).is_err());
+        assert!(parse_args(&args("run a --fuel -1")).is_err());
+        assert!(parse_args(&args("run a --overflow ignore")).is_err());
+    }
+
+    /// Writes `text` to `exit.asm` in an empty directory of its own, so tests
+    /// running in parallel don't share files.
+    fn write_source(test: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("porul-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("exit.asm");
+        fs::write(&source, text).unwrap();
+        source
+    }
+
+    fn run_plain(source: &Path, exit_register: usize) -> Result<i32, String> {
+        run(source, exit_register, None, false, None, OverflowMode::Wrap)
+    }
+
+    #[test]
+    fn test_asm_then_run() {
+        let source = write_source("asm", "load $1 #42\nhlt");
+        let image = source.with_extension("prl");
+        assert_eq!(run_plain(&source, 1), Ok(42));
+        asm(&source, &image).unwrap();
+        assert_eq!(run_plain(&image, 1), Ok(42));
+        assert!(disasm(&image, false).unwrap().contains("0004  00 00 00 00  hlt"));
+        assert_eq!(disasm(&image, true).unwrap(), "load $1 #42\nhlt\n");
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_run_reports_runtime_errors() {
+        let source = write_source("fault", "load $0 #1\ndiv $0 $1 $2\nhlt");
+        assert!(run_plain(&source, 0).unwrap_err().contains("runtime error"));
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_run_writes_trace() {
+        let source = write_source("trace", "load $1 #42\nhlt");
+        let log = source.with_extension("log");
+        assert_eq!(run(&source, 1, Some(&(log.clone(), TraceFormat::Text)), false, None, OverflowMode::Wrap), Ok(42));
+        assert_eq!(fs::read_to_string(&log).unwrap(), "0000  load $1 #42           $1: 0 -> 42\n0004  hlt\n");
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_run_overflow_modes() {
+        let source = write_source("overflow", "load $1 #32767\nloadhi $1 #32767\nmul $1 $1 $0\nhlt");
+        assert_eq!(run(&source, 0, None, false, None, OverflowMode::Saturate), Ok(i32::MAX));
+        assert!(run(&source, 0, None, false, None, OverflowMode::Trap).unwrap_err().contains("arithmetic overflow"));
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_run_with_fuel() {
+        let source = write_source("fuel", "jmp #0");
+        assert!(run(&source, 0, None, false, Some(100), OverflowMode::Wrap).unwrap_err().contains("ran out of fuel after using 100 units"));
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_run_verifies_program() {
+        let source = write_source("verify", "jmp #40");
+        let err = run_plain(&source, 0).unwrap_err();
+        assert!(err.contains("failed verification"));
+        assert!(err.contains("0000: jump target 40 is outside the program"));
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_exit_code_range() {
+        let source = write_source("exit", "");
+        let run_source = |text: &str| {
+            fs::write(&source, text).unwrap();
+            main(&args(&format!("run {}", source.display())))
+        };
+        assert_eq!(run_source("load $0 #249\nhlt"), 249);
+        assert_eq!(run_source("load $0 #256\nhlt"), EXIT_FAILURE);
+        assert_eq!(run_source("load $0 #-1\nhlt"), EXIT_FAILURE);
+        assert_eq!(run_source("load $0 #250\nhlt"), EXIT_FAILURE);
+        assert_eq!(main(&args("launch")), EXIT_USAGE);
+        assert!(exit_code(&source, 3, 256).unwrap_err().ends_with("$3 holds 256, outside the exit code range 0 to 249"));
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
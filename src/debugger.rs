@@ -0,0 +1,295 @@
+use std::{collections::BTreeSet, fmt};
+
+use crate::assembler::symbols::SymbolTable;
+use crate::disassembler::{instruction_at, DisassembledInstruction};
+use crate::instruction::Opcode;
+use crate::vm::{StepOutcome, VmError, VM};
+
+/// A piece of VM state whose changes stop execution.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Watchpoint {
+    Register(usize),
+    Comparison,
+}
+
+impl Watchpoint {
+    fn value(&self, vm: &VM) -> i64 {
+        match self {
+            Watchpoint::Register(register) => vm.registers[*register] as i64,
+            Watchpoint::Comparison => vm.comparison_result() as i64,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watchpoint::Register(register) => write!(f, "${register}"),
+            Watchpoint::Comparison => write!(f, "comparison flag"),
+        }
+    }
+}
+
+/// Why the debugger handed control back.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    /// The requested step completed.
+    Stepped,
+    Breakpoint { offset: usize },
+    Watchpoint { watchpoint: Watchpoint, old: i64, new: i64 },
+    /// The program halted or ran off its end.
+    Finished(StepOutcome),
+}
+
+/// Breakpoints, watchpoints and symbols for driving a `VM` one instruction
+/// at a time.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    symbols: SymbolTable,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Replaces the symbols used to resolve label locations.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Resolves a location given as a decimal offset or a label name.
+    pub fn resolve(&self, location: &str) -> Result<usize, String> {
+        location
+            .parse::<usize>()
+            .ok()
+            .or_else(|| self.symbols.code_offset(location.trim_start_matches('@')).map(|offset| offset as usize))
+            .ok_or_else(|| format!("`{location}` is neither an offset nor a known label"))
+    }
+
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<usize, String> {
+        let offset = self.resolve(location)?;
+        self.breakpoints.insert(offset);
+        Ok(offset)
+    }
+
+    pub fn remove_breakpoint(&mut self, location: &str) -> Result<usize, String> {
+        let offset = self.resolve(location)?;
+        if !self.breakpoints.remove(&offset) {
+            return Err(format!("no breakpoint at {offset}"));
+        }
+        Ok(offset)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &usize> {
+        self.breakpoints.iter()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watched| *watched != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// The instruction the VM will execute next.
+    pub fn current_instruction(&self, vm: &VM) -> Option<DisassembledInstruction> {
+        instruction_at(vm.program(), vm.pc())
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self, vm: &mut VM) -> Result<StopReason, VmError> {
+        let before: Vec<i64> = self.watchpoints.iter().map(|watchpoint| watchpoint.value(vm)).collect();
+        let outcome = vm.run_once()?;
+        for (watchpoint, old) in self.watchpoints.iter().zip(before) {
+            let new = watchpoint.value(vm);
+            if new != old {
+                return Ok(StopReason::Watchpoint { watchpoint: *watchpoint, old, new });
+            }
+        }
+        match outcome {
+            StepOutcome::Continue => Ok(StopReason::Stepped),
+            finished => Ok(StopReason::Finished(finished)),
+        }
+    }
+
+    /// Executes a single instruction, running a CALL through to its return.
+    pub fn step_over(&mut self, vm: &mut VM) -> Result<StopReason, VmError> {
+        let is_call = self.current_instruction(vm).is_some_and(|instruction| instruction.opcode == Opcode::CALL);
+        let depth = vm.call_stack().len();
+        let mut reason = self.step(vm)?;
+        while is_call && reason == StopReason::Stepped && vm.call_stack().len() > depth {
+            reason = self.step_or_break(vm)?;
+        }
+        Ok(reason)
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program. The
+    /// instruction at the current position always executes, so continuing from
+    /// a breakpoint makes progress.
+    pub fn continue_execution(&mut self, vm: &mut VM) -> Result<StopReason, VmError> {
+        let mut reason = self.step(vm)?;
+        while reason == StopReason::Stepped {
+            reason = self.step_or_break(vm)?;
+        }
+        Ok(reason)
+    }
+
+    /// Undoes the last executed instruction. Needs the VM to record history.
+    pub fn step_back(&mut self, vm: &mut VM) -> Result<(), String> {
+        if vm.instruction_count().is_none() {
+            return Err("history is not being recorded".to_string());
+        }
+        match vm.step_back() {
+            true => Ok(()),
+            false => Err("no earlier instruction is recorded".to_string()),
+        }
+    }
+
+    /// Moves to the point where `count` instructions have executed since
+    /// history recording began: backwards through the undo log, or forwards
+    /// by stepping, stopping early for watchpoints or the end of the program.
+    pub fn goto(&mut self, vm: &mut VM, count: u64) -> Result<StopReason, String> {
+        let current = vm.instruction_count().ok_or("history is not being recorded")?;
+        if count <= current {
+            vm.rewind_to(count)
+                .map_err(|earliest| format!("instruction {count} is older than the earliest recorded, {earliest}"))?;
+            return Ok(StopReason::Stepped);
+        }
+        while vm.instruction_count() < Some(count) {
+            match self.step(vm).map_err(|err| err.to_string())? {
+                StopReason::Stepped => (),
+                reason => return Ok(reason),
+            }
+        }
+        Ok(StopReason::Stepped)
+    }
+
+    fn step_or_break(&mut self, vm: &mut VM) -> Result<StopReason, VmError> {
+        if self.breakpoints.contains(&vm.pc()) {
+            return Ok(StopReason::Breakpoint { offset: vm.pc() });
+        }
+        self.step(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn load(source: &str) -> (Debugger, VM) {
+        let image = assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load(&image.to_bytes()).unwrap();
+        vm.enable_history(1000);
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(image.symbols.unwrap_or_default());
+        (debugger, vm)
+    }
+
+    const PROGRAM: &str = "
+        load $0 #3
+        load $1 #1
+        load $4 @loop
+        loop: sub $0 $1 $0
+        call @count
+        load $2 #0
+        neq $0 $2
+        jeq $4
+        hlt
+        count: add $3 $1 $3
+        ret
+    ";
+
+    #[test]
+    fn test_breakpoints_by_offset_and_label() {
+        let (mut debugger, mut vm) = load(PROGRAM);
+        assert_eq!(debugger.add_breakpoint("count"), Ok(36));
+        assert!(debugger.add_breakpoint("nowhere").is_err());
+
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(StopReason::Breakpoint { offset: 36 }));
+        assert_eq!(debugger.current_instruction(&vm).unwrap().to_string(), "add $3 $1 $3");
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(StopReason::Breakpoint { offset: 36 }));
+        assert_eq!(vm.registers[3], 1);
+
+        assert_eq!(debugger.remove_breakpoint("36"), Ok(36));
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(StopReason::Finished(StepOutcome::Halted)));
+        assert_eq!(vm.registers[3], 3);
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let (mut debugger, mut vm) = load(PROGRAM);
+        for _ in 0..4 {
+            assert_eq!(debugger.step(&mut vm), Ok(StopReason::Stepped));
+        }
+        assert_eq!(debugger.current_instruction(&vm).unwrap().opcode, Opcode::CALL);
+        assert_eq!(debugger.step_over(&mut vm), Ok(StopReason::Stepped));
+        assert_eq!(vm.pc(), 20);
+        assert_eq!(vm.registers[3], 1);
+
+        debugger.add_breakpoint("count").unwrap();
+        for _ in 0..4 {
+            debugger.step(&mut vm).unwrap();
+        }
+        assert_eq!(debugger.step_over(&mut vm), Ok(StopReason::Breakpoint { offset: 36 }));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut debugger, mut vm) = load(PROGRAM);
+        debugger.add_watchpoint(Watchpoint::Register(3));
+        debugger.add_watchpoint(Watchpoint::Comparison);
+        assert_eq!(
+            debugger.continue_execution(&mut vm),
+            Ok(StopReason::Watchpoint { watchpoint: Watchpoint::Register(3), old: 0, new: 1 })
+        );
+        assert_eq!(
+            debugger.continue_execution(&mut vm),
+            Ok(StopReason::Watchpoint { watchpoint: Watchpoint::Comparison, old: 0, new: 1 })
+        );
+        assert!(debugger.remove_watchpoint(Watchpoint::Register(3)));
+        assert!(!debugger.remove_watchpoint(Watchpoint::Register(3)));
+        assert_eq!(
+            debugger.continue_execution(&mut vm),
+            Ok(StopReason::Watchpoint { watchpoint: Watchpoint::Comparison, old: 1, new: 0 })
+        );
+    }
+
+    #[test]
+    fn test_step_back_and_goto() {
+        let (mut debugger, mut vm) = load(PROGRAM);
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(StopReason::Finished(StepOutcome::Halted)));
+        let total = vm.instruction_count().unwrap();
+
+        debugger.step_back(&mut vm).unwrap();
+        assert_eq!(debugger.current_instruction(&vm).unwrap().opcode, Opcode::HLT);
+        assert_eq!(debugger.goto(&mut vm, 5), Ok(StopReason::Stepped));
+        assert_eq!(debugger.current_instruction(&vm).unwrap().to_string(), "add $3 $1 $3");
+        assert_eq!((vm.registers[0], vm.registers[3]), (2, 0));
+
+        debugger.add_watchpoint(Watchpoint::Register(3));
+        assert_eq!(
+            debugger.goto(&mut vm, total),
+            Ok(StopReason::Watchpoint { watchpoint: Watchpoint::Register(3), old: 0, new: 1 })
+        );
+        assert_eq!(vm.instruction_count(), Some(6));
+
+        assert_eq!(debugger.goto(&mut vm, 0), Ok(StopReason::Stepped));
+        assert_eq!(vm.pc(), 0);
+        assert!(debugger.step_back(&mut vm).is_err());
+        assert!(debugger.goto(&mut VM::new(), 0).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+
+use crate::assembler::symbols::{Section, SymbolTable};
+use crate::assembler::Token;
+use crate::instruction::{Opcode, OperandKind, INSTRUCTION_LENGTH};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisassembledInstruction {
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<Token>,
+    pub bytes: Vec<u8>,
+    /// Set when the bytes do not decode to a valid instruction.
+    pub problem: Option<String>,
+}
+
+impl DisassembledInstruction {
+    pub fn is_illegal(&self) -> bool {
+        self.problem.is_some()
+    }
+}
+
+/// Renders the instruction in assembler syntax. Illegal instructions render as
+/// `igl`, which the assembler refuses, so they cannot pass for real code.
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_illegal() {
+            return write!(f, "{}", Token::Op { code: Opcode::IGL });
+        }
+        write!(f, "{}", Token::Op { code: self.opcode })?;
+        for operand in &self.operands {
+            write!(f, " {operand}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes `program` one instruction at a time.
+pub fn disassemble(program: &[u8]) -> Vec<DisassembledInstruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while let Some(instruction) = instruction_at(program, offset) {
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Decodes the single instruction starting at `offset`. Illegal opcodes are
+/// taken to occupy `INSTRUCTION_LENGTH` bytes.
+pub fn instruction_at(program: &[u8], offset: usize) -> Option<DisassembledInstruction> {
+    if offset >= program.len() {
+        return None;
+    }
+    let length = match Opcode::from(program[offset]) {
+        Opcode::IGL => INSTRUCTION_LENGTH,
+        opcode => opcode.info().length(),
+    };
+    let end = program.len().min(offset + length);
+    Some(decode(offset, &program[offset..end]))
+}
+
+fn decode(offset: usize, bytes: &[u8]) -> DisassembledInstruction {
+    let opcode = Opcode::from(bytes[0]);
+    let mut instruction = DisassembledInstruction {
+        offset,
+        opcode,
+        operands: vec![],
+        bytes: bytes.to_vec(),
+        problem: None,
+    };
+    if opcode == Opcode::IGL {
+        instruction.problem = Some(format!("illegal opcode {:#04x}", bytes[0]));
+        return instruction;
+    }
+
+    let mut position = 1;
+    for kind in opcode.info().operands {
+        let Some(operand) = bytes.get(position..position + kind.width()) else {
+            instruction.problem = Some("truncated instruction".to_string());
+            return instruction;
+        };
+        let value = operand.iter().fold(0u64, |value, byte| value << 8 | *byte as u64);
+        instruction.operands.push(match kind {
+            OperandKind::Register => Token::Register { reg_number: value as u8 },
+            OperandKind::FloatRegister => Token::FloatRegister { reg_number: value as u8 },
+            OperandKind::Immediate16 | OperandKind::Immediate24 => Token::IntegerOperand { value: value as i32 },
+            OperandKind::SignedImmediate16 => Token::IntegerOperand { value: value as i16 as i32 },
+            OperandKind::Float64 => Token::FloatOperand { value: f64::from_bits(value) },
+        });
+        position += kind.width();
+    }
+    if bytes.len() < opcode.info().length() {
+        instruction.problem = Some("truncated instruction".to_string());
+    }
+    instruction
+}
+
+/// Renders `program` as assembler source that assembles back to the same
+/// instructions, declaring any labels found in `symbols`. Fails at the first
+/// illegal or truncated instruction, which no source can reproduce.
+pub fn source(program: &[u8], symbols: Option<&SymbolTable>) -> Result<String, String> {
+    let mut text = String::new();
+    for instruction in disassemble(program) {
+        if let Some(problem) = &instruction.problem {
+            return Err(format!("{:04}: {problem} cannot be written as source", instruction.offset));
+        }
+        for label in labels_at(symbols, Section::Code, instruction.offset) {
+            text += &format!("{label}:\n");
+        }
+        text += &format!("{instruction}\n");
+    }
+    Ok(text)
+}
+
+/// Number of values written on each `.byte` line by `data_source`.
+const BYTES_PER_LINE: usize = 8;
+
+/// Renders read-only data as a `.data` section of `.byte` directives that
+/// ends with `.code`, starting a new line at every data label in `symbols`.
+/// Empty data renders as nothing.
+pub fn data_source(ro_data: &[u8], symbols: Option<&SymbolTable>) -> String {
+    if ro_data.is_empty() {
+        return String::new();
+    }
+    let mut text = ".data\n".to_string();
+    let mut line: Vec<String> = vec![];
+    for (offset, byte) in ro_data.iter().enumerate() {
+        let labels = labels_at(symbols, Section::Data, offset);
+        if !line.is_empty() && (!labels.is_empty() || line.len() == BYTES_PER_LINE) {
+            text += &format!(".byte {}\n", line.join(" "));
+            line.clear();
+        }
+        for label in labels {
+            text += &format!("{label}:\n");
+        }
+        line.push(format!("#{byte}"));
+    }
+    text += &format!(".byte {}\n.code\n", line.join(" "));
+    text
+}
+
+/// Renders `program` with offsets and raw bytes, flagging illegal instructions.
+pub fn listing(program: &[u8], symbols: Option<&SymbolTable>) -> String {
+    let mut text = String::new();
+    for instruction in disassemble(program) {
+        for label in labels_at(symbols, Section::Code, instruction.offset) {
+            text += &format!("{label}:\n");
+        }
+        let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        let line = format!("{:04}  {:<11}  {instruction}", instruction.offset, bytes.join(" "));
+        match &instruction.problem {
+            Some(problem) => text += &format!("{line:<36}<- {problem}\n"),
+            None => text += &format!("{line}\n"),
+        }
+    }
+    text
+}
+
+fn labels_at(symbols: Option<&SymbolTable>, section: Section, offset: usize) -> Vec<&str> {
+    symbols
+        .into_iter()
+        .flat_map(|symbols| symbols.iter())
+        .filter(|symbol| symbol.section == section && symbol.offset as usize == offset)
+        .map(|symbol| symbol.name.as_str())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_disassemble_instructions() {
+        let instructions = disassemble(&[1, 2, 1, 244, 2, 0, 1, 2, 6, 0, 1, 4]);
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[0].to_string(), "load $2 #500");
+        assert_eq!(instructions[1].to_string(), "add $0 $1 $2");
+        assert_eq!(instructions[2].offset, 8);
+        assert_eq!(instructions[2].to_string(), "jmp #260");
+
+        let program = [1, 2, 1, 244, 2, 0, 1, 2];
+        assert_eq!(instruction_at(&program, 4).unwrap().to_string(), "add $0 $1 $2");
+        assert_eq!(instruction_at(&program, 8), None);
+    }
+
+    #[test]
+    fn test_disassemble_float_instructions() {
+        let image = assemble("loadf64 $f1 #-2.5\naddf64 $f1 $f1 $f2\nhlt").unwrap();
+        let instructions = disassemble(&image.code);
+        assert_eq!(instructions.iter().map(|instruction| instruction.offset).collect::<Vec<_>>(), vec![0, 12, 16]);
+        assert_eq!(instructions[0].to_string(), "loadf64 $f1 #-2.5");
+        assert_eq!(instructions[1].to_string(), "addf64 $f1 $f1 $f2");
+
+        let truncated = disassemble(&image.code[..8]);
+        assert_eq!(truncated.len(), 1);
+        assert_eq!(truncated[0].problem, Some("truncated instruction".to_string()));
+    }
+
+    #[test]
+    fn test_disassemble_signed_immediates() {
+        let image = assemble("lti $1 #-2\nload $1 #-2").unwrap();
+        let instructions = disassemble(&image.code);
+        assert_eq!(instructions[0].to_string(), "lti $1 #-2");
+        assert_eq!(instructions[1].to_string(), "load $1 #65534");
+    }
+
+    #[test]
+    fn test_data_source_round_trip() {
+        let text = ".data\nempty: .asciiz \"\"\nwords: .word #1 #2 #-1\n.code\nmain: la $1 @words\nloadr32 $2 $1\nhlt";
+        let image = assemble(text).unwrap();
+        let text = data_source(&image.ro_data, image.symbols.as_ref());
+        assert_eq!(
+            text,
+            ".data\nempty:\n.byte #0\nwords:\n.byte #0 #0 #0 #1 #0 #0 #0 #2\n.byte #255 #255 #255 #255\n.code\n"
+        );
+        let rebuilt = assemble(&(text + &source(&image.code, image.symbols.as_ref()).unwrap())).unwrap();
+        assert_eq!(rebuilt, image);
+        assert_eq!(data_source(&[], None), "");
+    }
+
+    #[test]
+    fn test_disassemble_flags_illegal_bytes() {
+        let instructions = disassemble(&[200, 0, 0, 0, 1, 0]);
+        assert_eq!(instructions[0].problem, Some("illegal opcode 0xc8".to_string()));
+        assert_eq!(instructions[0].to_string(), "igl");
+        assert_eq!(instructions[1].problem, Some("truncated instruction".to_string()));
+        assert_eq!(disassemble(&[0, 0])[0].problem, Some("truncated instruction".to_string()));
+
+        let text = listing(&[200, 0, 0, 0], None);
+        assert!(text.starts_with("0000  c8 00 00 00  igl"));
+        assert!(text.contains("<- illegal opcode 0xc8"));
+
+        assert_eq!(source(&[0, 0, 0, 0, 200, 0, 0, 0], None), Err("0004: illegal opcode 0xc8 cannot be written as source".to_string()));
+        assert!(assemble("igl").is_err());
+    }
+
+    #[test]
+    fn test_source_round_trips() {
+        let original = "main: load $1 #10\nloop: sub $1 $2 $1\nneq $1 $3\njmpb @loop\nhlt";
+        let image = assemble(original).unwrap();
+        let text = source(&image.code, image.symbols.as_ref()).unwrap();
+        assert!(text.starts_with("main:\nload $1 #10\nloop:\nsub $1 $2 $1\n"));
+
+        let reassembled = assemble(&text).unwrap();
+        assert_eq!(reassembled, image);
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use crate::instruction::Opcode;
+
+/// Fuel charged for an opcode missing from the cost table.
+pub const DEFAULT_COST: u64 = 1;
+
+/// The fuel each opcode costs to execute. Opcodes without an entry cost
+/// `DEFAULT_COST`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CostTable {
+    costs: HashMap<Opcode, u64>,
+}
+
+impl Default for CostTable {
+    /// Division, calls and memory access cost more than register arithmetic,
+    /// and ALOC costs the most since it grows the heap.
+    fn default() -> Self {
+        let mut table = CostTable::uniform();
+        table.set(Opcode::MUL, 2);
+        table.set(Opcode::MULW, 2);
+        table.set(Opcode::MULC, 2);
+        table.set(Opcode::DIV, 4);
+        table.set(Opcode::MOD, 4);
+        table.set(Opcode::MULF64, 2);
+        table.set(Opcode::DIVF64, 4);
+        table.set(Opcode::CALL, 2);
+        table.set(Opcode::RET, 2);
+        table.set(Opcode::ALOC, 10);
+        for opcode in [
+            Opcode::LOADM8,
+            Opcode::LOADM16,
+            Opcode::LOADM32,
+            Opcode::STOREM8,
+            Opcode::STOREM16,
+            Opcode::STOREM32,
+            Opcode::LOADR8,
+            Opcode::LOADR16,
+            Opcode::LOADR32,
+        ] {
+            table.set(opcode, 2);
+        }
+        table
+    }
+}
+
+impl CostTable {
+    /// A table where every opcode costs `DEFAULT_COST`.
+    pub fn uniform() -> CostTable {
+        CostTable { costs: HashMap::new() }
+    }
+
+    pub fn set(&mut self, opcode: Opcode, cost: u64) {
+        self.costs.insert(opcode, cost);
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs.get(&opcode).copied().unwrap_or(DEFAULT_COST)
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+/// Number of instructions a history remembers unless told otherwise.
+pub const DEFAULT_HISTORY_LIMIT: usize = 1 << 20;
+
+/// The state one executed instruction overwrote, enough to undo it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UndoEntry {
+    pub pc: usize,
+    /// The old value of every register the instruction changed.
+    pub registers: Vec<(usize, i32)>,
+    pub float_registers: Vec<(usize, f64)>,
+    pub comparison_result: bool,
+    pub overflow: bool,
+    pub remainder: i32,
+    pub fuel: Option<u64>,
+    pub fuel_used: u64,
+    pub stack_length: usize,
+    /// The value a POP removed from the stack.
+    pub popped: Option<i32>,
+    pub call_stack_length: usize,
+    /// The return address a RET removed from the call stack.
+    pub returned_to: Option<usize>,
+    pub heap_length: usize,
+    /// The start and old contents of heap bytes a store overwrote.
+    pub overwritten: Option<(usize, Vec<u8>)>,
+}
+
+/// An undo log of the most recently executed instructions. Once full, the
+/// oldest entries are forgotten and can no longer be stepped back to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct History {
+    entries: VecDeque<UndoEntry>,
+    limit: usize,
+    /// Instructions executed before the oldest remembered entry.
+    forgotten: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_HISTORY_LIMIT)
+    }
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History { entries: VecDeque::new(), limit, forgotten: 0 }
+    }
+
+    pub fn push(&mut self, entry: UndoEntry) {
+        if self.limit == 0 {
+            self.forgotten += 1;
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+            self.forgotten += 1;
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        self.entries.pop_back()
+    }
+
+    /// Number of instructions executed since recording started.
+    pub fn instruction_count(&self) -> u64 {
+        self.forgotten + self.entries.len() as u64
+    }
+
+    /// The earliest instruction count that can still be stepped back to.
+    pub fn earliest(&self) -> u64 {
+        self.forgotten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pc: usize) -> UndoEntry {
+        UndoEntry {
+            pc,
+            registers: vec![],
+            float_registers: vec![],
+            comparison_result: false,
+            overflow: false,
+            remainder: 0,
+            fuel: None,
+            fuel_used: 0,
+            stack_length: 0,
+            popped: None,
+            call_stack_length: 0,
+            returned_to: None,
+            heap_length: 0,
+            overwritten: None,
+        }
+    }
+
+    #[test]
+    fn test_history_forgets_oldest_entries() {
+        let mut history = History::new(2);
+        for pc in [0, 4, 8] {
+            history.push(entry(pc));
+        }
+        assert_eq!(history.instruction_count(), 3);
+        assert_eq!(history.earliest(), 1);
+        assert_eq!(history.pop().map(|entry| entry.pc), Some(8));
+        assert_eq!(history.pop().map(|entry| entry.pc), Some(4));
+        assert_eq!(history.pop(), None);
+        assert_eq!(history.instruction_count(), 1);
+    }
+
+    #[test]
+    fn test_history_without_entries_still_counts() {
+        let mut history = History::new(0);
+        for pc in [0, 4, 8] {
+            history.push(entry(pc));
+        }
+        assert_eq!(history.instruction_count(), 3);
+        assert_eq!(history.earliest(), 3);
+        assert_eq!(history.pop(), None);
+    }
+}
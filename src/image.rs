@@ -0,0 +1,270 @@
+//! The executable image produced by the assembler and loaded by the VM.
+//!
+//! All multi-byte fields are big-endian, like instruction operands:
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic, `PORL`                                |
+//! | 4      | 2    | format version                               |
+//! | 6      | 2    | flags, bit 0 set when a symbol table follows |
+//! | 8      | 4    | entry point, an offset into the code section |
+//! | 12     | 4    | read-only data section length                |
+//! | 16     | 4    | code section length                          |
+//! | 20     | 4    | symbol table length                          |
+//! | 24     | ...  | read-only data, code, then symbol table      |
+//!
+//! Each symbol table entry is a 4-byte offset, a 1-byte section (0 for code,
+//! 1 for read-only data), a 2-byte name length and the UTF-8 name. Version 1
+//! images have no section byte and only code symbols; they still load.
+use std::{error, fmt};
+
+use crate::assembler::symbols::{Section, Symbol, SymbolTable};
+use crate::instruction::INSTRUCTION_LENGTH;
+
+pub const MAGIC: [u8; 4] = *b"PORL";
+pub const VERSION: u16 = 2;
+pub const HEADER_LENGTH: usize = 24;
+
+const FLAG_SYMBOLS: u16 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ImageError {
+    TooShort { length: usize },
+    BadMagic,
+    UnsupportedVersion { version: u16 },
+    /// The section lengths in the header do not add up to the image length.
+    LengthMismatch { expected: usize, actual: usize },
+    MisalignedCode { length: usize },
+    EntryPointOutOfBounds { entry_point: u32 },
+    BadSymbolTable,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::TooShort { length } => write!(f, "image is {length} bytes, shorter than its {HEADER_LENGTH} byte header"),
+            ImageError::BadMagic => write!(f, "not a porul image (bad magic number)"),
+            ImageError::UnsupportedVersion { version } => write!(f, "unsupported image version {version}"),
+            ImageError::LengthMismatch { expected, actual } => {
+                write!(f, "header describes {expected} bytes but the image is {actual} bytes")
+            }
+            ImageError::MisalignedCode { length } => {
+                write!(f, "code section length {length} is not a multiple of {INSTRUCTION_LENGTH}")
+            }
+            ImageError::EntryPointOutOfBounds { entry_point } => {
+                write!(f, "entry point {entry_point} is not an instruction in the code section")
+            }
+            ImageError::BadSymbolTable => write!(f, "symbol table is malformed"),
+        }
+    }
+}
+
+impl error::Error for ImageError {}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Image {
+    pub entry_point: u32,
+    pub ro_data: Vec<u8>,
+    pub code: Vec<u8>,
+    pub symbols: Option<SymbolTable>,
+}
+
+impl Image {
+    pub fn new(code: Vec<u8>) -> Image {
+        Image {
+            code,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true when `bytes` starts with the image magic number.
+    pub fn is_image(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let symbols = self.symbols.as_ref().map(encode_symbols).unwrap_or_default();
+        let flags = if self.symbols.is_some() { FLAG_SYMBOLS } else { 0 };
+
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.ro_data.len() + self.code.len() + symbols.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&flags.to_be_bytes());
+        bytes.extend_from_slice(&self.entry_point.to_be_bytes());
+        bytes.extend_from_slice(&(self.ro_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(symbols.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.ro_data);
+        bytes.extend_from_slice(&self.code);
+        bytes.extend_from_slice(&symbols);
+        bytes
+    }
+
+    /// Parses and validates an image.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, ImageError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(ImageError::TooShort { length: bytes.len() });
+        }
+        if !Image::is_image(bytes) {
+            return Err(ImageError::BadMagic);
+        }
+        let version = read_u16(bytes, 4);
+        if version != 1 && version != VERSION {
+            return Err(ImageError::UnsupportedVersion { version });
+        }
+        let flags = read_u16(bytes, 6);
+        let entry_point = read_u32(bytes, 8);
+        let ro_data_length = read_u32(bytes, 12) as usize;
+        let code_length = read_u32(bytes, 16) as usize;
+        let symbols_length = read_u32(bytes, 20) as usize;
+
+        let expected = HEADER_LENGTH + ro_data_length + code_length + symbols_length;
+        if expected != bytes.len() {
+            return Err(ImageError::LengthMismatch { expected, actual: bytes.len() });
+        }
+        if !code_length.is_multiple_of(INSTRUCTION_LENGTH) {
+            return Err(ImageError::MisalignedCode { length: code_length });
+        }
+        let entry = entry_point as usize;
+        if !entry.is_multiple_of(INSTRUCTION_LENGTH) || (entry >= code_length && entry != 0) {
+            return Err(ImageError::EntryPointOutOfBounds { entry_point });
+        }
+
+        let code_start = HEADER_LENGTH + ro_data_length;
+        let symbols_start = code_start + code_length;
+        let symbols = if flags & FLAG_SYMBOLS != 0 {
+            Some(decode_symbols(&bytes[symbols_start..], version)?)
+        } else if symbols_length != 0 {
+            return Err(ImageError::BadSymbolTable);
+        } else {
+            None
+        };
+
+        Ok(Image {
+            entry_point,
+            ro_data: bytes[HEADER_LENGTH..code_start].to_vec(),
+            code: bytes[code_start..symbols_start].to_vec(),
+            symbols,
+        })
+    }
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn encode_symbols(symbols: &SymbolTable) -> Vec<u8> {
+    let mut bytes = vec![];
+    for symbol in symbols.iter() {
+        bytes.extend_from_slice(&symbol.offset.to_be_bytes());
+        bytes.push(match symbol.section {
+            Section::Code => 0,
+            Section::Data => 1,
+        });
+        bytes.extend_from_slice(&(symbol.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(symbol.name.as_bytes());
+    }
+    bytes
+}
+
+fn decode_symbols(mut bytes: &[u8], version: u16) -> Result<SymbolTable, ImageError> {
+    let mut symbols = SymbolTable::new();
+    let name_start = if version == 1 { 6 } else { 7 };
+    while !bytes.is_empty() {
+        if bytes.len() < name_start {
+            return Err(ImageError::BadSymbolTable);
+        }
+        let offset = read_u32(bytes, 0);
+        let section = match (version, bytes[4]) {
+            (1, _) | (_, 0) => Section::Code,
+            (_, 1) => Section::Data,
+            _ => return Err(ImageError::BadSymbolTable),
+        };
+        let name_length = read_u16(bytes, name_start - 2) as usize;
+        let name = bytes
+            .get(name_start..name_start + name_length)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or(ImageError::BadSymbolTable)?;
+        symbols.add_symbol(Symbol::in_section(name, offset, section));
+        bytes = &bytes[name_start + name_length..];
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> Image {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("main", 4));
+        symbols.add_symbol(Symbol::in_section("table", 1, Section::Data));
+        Image {
+            entry_point: 4,
+            ro_data: vec![1, 2, 3],
+            code: vec![0, 0, 0, 0, 1, 0, 1, 244],
+            symbols: Some(symbols),
+        }
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let image = test_image();
+        let bytes = image.to_bytes();
+        assert_eq!(&bytes[0..4], b"PORL");
+        assert_eq!(bytes.len(), HEADER_LENGTH + 3 + 8 + (4 + 1 + 2 + 4) + (4 + 1 + 2 + 5));
+        assert_eq!(Image::from_bytes(&bytes), Ok(image));
+
+        let image = Image::new(vec![0, 0, 0, 0]);
+        assert_eq!(Image::from_bytes(&image.to_bytes()), Ok(image));
+    }
+
+    #[test]
+    fn test_image_reads_version_1() {
+        let mut bytes = Image::new(vec![0, 0, 0, 0]).to_bytes();
+        bytes[5] = 1;
+        bytes[7] = FLAG_SYMBOLS as u8;
+        bytes[23] = 10;
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 4]);
+        bytes.extend_from_slice(b"main");
+        let image = Image::from_bytes(&bytes).unwrap();
+        assert_eq!(image.symbols.unwrap().code_offset("main"), Some(0));
+    }
+
+    #[test]
+    fn test_image_rejects_bad_headers() {
+        let bytes = test_image().to_bytes();
+        assert_eq!(Image::from_bytes(&bytes[..10]), Err(ImageError::TooShort { length: 10 }));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(Image::from_bytes(&bad_magic), Err(ImageError::BadMagic));
+
+        let mut bad_version = bytes.clone();
+        bad_version[5] = 9;
+        assert_eq!(Image::from_bytes(&bad_version), Err(ImageError::UnsupportedVersion { version: 9 }));
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(Image::from_bytes(truncated), Err(ImageError::LengthMismatch { .. })));
+    }
+
+    #[test]
+    fn test_image_rejects_bad_sections() {
+        let mut image = test_image();
+        image.entry_point = 8;
+        assert_eq!(Image::from_bytes(&image.to_bytes()), Err(ImageError::EntryPointOutOfBounds { entry_point: 8 }));
+
+        let mut image = test_image();
+        image.code.push(0);
+        assert_eq!(Image::from_bytes(&image.to_bytes()), Err(ImageError::MisalignedCode { length: 9 }));
+
+        let mut bytes = test_image().to_bytes();
+        let length = bytes.len();
+        bytes[length - 5] = 200;
+        assert_eq!(Image::from_bytes(&bytes), Err(ImageError::BadSymbolTable));
+    }
+}
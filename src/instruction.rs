@@ -1,4 +1,4 @@
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, Hash)]
 #[derive(Debug, Clone, Copy)]
 pub enum Opcode {
     HLT,
@@ -18,42 +18,299 @@ pub enum Opcode {
     GT,
     LT,
     JNEQ,
-    IGL,
+    PUSH,
+    POP,
+    CALL,
+    RET,
+    ALOC,
+    LOADM8,
+    LOADM16,
+    LOADM32,
+    STOREM8,
+    STOREM16,
+    STOREM32,
+    LOADHI,
+    LOADF64,
+    ADDF64,
+    SUBF64,
+    MULF64,
+    DIVF64,
+    EQF64,
+    NEQF64,
+    GTF64,
+    LTF64,
+    GEQF64,
+    LEQF64,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+    MOD,
+    ADDW,
+    SUBW,
+    MULW,
+    ADDC,
+    SUBC,
+    MULC,
+    GETREM,
+    EQI,
+    NEQI,
+    GTI,
+    LTI,
+    GEQI,
+    LEQI,
+    SETCMP,
+    SETNCMP,
+    BEQ,
+    BNE,
+    BGT,
+    BLT,
+    BGE,
+    BLE,
+    LA,
+    LOADR8,
+    LOADR16,
+    LOADR32,
+    IGL = 255,
 }
 
-pub struct Instruction {
-    opcode: Opcode
+/// The kind of an operand following the opcode byte of an instruction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    /// A register index, encoded in one byte.
+    Register,
+    /// An immediate value, encoded big-endian in two bytes.
+    Immediate16,
+    /// A signed immediate value, encoded big-endian in two bytes.
+    SignedImmediate16,
+    /// An immediate value, encoded big-endian in three bytes.
+    Immediate24,
+    /// A float register index, encoded in one byte.
+    FloatRegister,
+    /// A 64-bit float, encoded big-endian in eight bytes.
+    Float64,
 }
 
-impl Instruction {
-    fn new(opcode: Opcode) -> Instruction {
-        Instruction {
-            opcode
+impl OperandKind {
+    pub fn width(&self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister => 1,
+            OperandKind::Immediate16 | OperandKind::SignedImmediate16 => 2,
+            OperandKind::Immediate24 => 3,
+            OperandKind::Float64 => 8,
         }
     }
 }
 
+/// Every instruction is padded to a multiple of this many bytes. Only
+/// instructions with a float immediate are longer than one multiple.
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// The assembler mnemonic and operand layout of an opcode.
+#[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub operands: &'static [OperandKind],
+}
+
+use OperandKind::*;
+
+const NO_OPERANDS: &[OperandKind] = &[];
+const ONE_REGISTER: &[OperandKind] = &[Register];
+const TWO_REGISTERS: &[OperandKind] = &[Register, Register];
+const THREE_REGISTERS: &[OperandKind] = &[Register, Register, Register];
+const JUMP_TARGET: &[OperandKind] = &[Immediate24];
+const REGISTER_AND_CONSTANT: &[OperandKind] = &[Register, SignedImmediate16];
+const BRANCH: &[OperandKind] = &[Register, Register, Immediate24];
+const TWO_FLOAT_REGISTERS: &[OperandKind] = &[FloatRegister, FloatRegister];
+const THREE_FLOAT_REGISTERS: &[OperandKind] = &[FloatRegister, FloatRegister, FloatRegister];
+
+pub const OPCODE_TABLE: &[OpcodeInfo] = &[
+    OpcodeInfo { opcode: Opcode::HLT, mnemonic: "hlt", operands: NO_OPERANDS },
+    OpcodeInfo { opcode: Opcode::LOAD, mnemonic: "load", operands: &[Register, Immediate16] },
+    OpcodeInfo { opcode: Opcode::ADD, mnemonic: "add", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SUB, mnemonic: "sub", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::MUL, mnemonic: "mul", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::DIV, mnemonic: "div", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::JMP, mnemonic: "jmp", operands: JUMP_TARGET },
+    OpcodeInfo { opcode: Opcode::JMPF, mnemonic: "jmpf", operands: JUMP_TARGET },
+    OpcodeInfo { opcode: Opcode::JMPB, mnemonic: "jmpb", operands: JUMP_TARGET },
+    OpcodeInfo { opcode: Opcode::EQ, mnemonic: "eq", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::NEQ, mnemonic: "neq", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::JEQ, mnemonic: "jeq", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::GEQ, mnemonic: "geq", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LEQ, mnemonic: "leq", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::GT, mnemonic: "gt", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LT, mnemonic: "lt", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::JNEQ, mnemonic: "jneq", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::PUSH, mnemonic: "push", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::POP, mnemonic: "pop", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::CALL, mnemonic: "call", operands: JUMP_TARGET },
+    OpcodeInfo { opcode: Opcode::RET, mnemonic: "ret", operands: NO_OPERANDS },
+    OpcodeInfo { opcode: Opcode::ALOC, mnemonic: "aloc", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::LOADM8, mnemonic: "loadm8", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LOADM16, mnemonic: "loadm16", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LOADM32, mnemonic: "loadm32", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::STOREM8, mnemonic: "storem8", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::STOREM16, mnemonic: "storem16", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::STOREM32, mnemonic: "storem32", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LOADHI, mnemonic: "loadhi", operands: &[Register, Immediate16] },
+    OpcodeInfo { opcode: Opcode::LOADF64, mnemonic: "loadf64", operands: &[FloatRegister, Float64] },
+    OpcodeInfo { opcode: Opcode::ADDF64, mnemonic: "addf64", operands: THREE_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SUBF64, mnemonic: "subf64", operands: THREE_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::MULF64, mnemonic: "mulf64", operands: THREE_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::DIVF64, mnemonic: "divf64", operands: THREE_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::EQF64, mnemonic: "eqf64", operands: TWO_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::NEQF64, mnemonic: "neqf64", operands: TWO_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::GTF64, mnemonic: "gtf64", operands: TWO_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LTF64, mnemonic: "ltf64", operands: TWO_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::GEQF64, mnemonic: "geqf64", operands: TWO_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LEQF64, mnemonic: "leqf64", operands: TWO_FLOAT_REGISTERS },
+    OpcodeInfo { opcode: Opcode::AND, mnemonic: "and", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::OR, mnemonic: "or", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::XOR, mnemonic: "xor", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::NOT, mnemonic: "not", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SHL, mnemonic: "shl", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SHR, mnemonic: "shr", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SAR, mnemonic: "sar", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::MOD, mnemonic: "mod", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::ADDW, mnemonic: "addw", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SUBW, mnemonic: "subw", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::MULW, mnemonic: "mulw", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::ADDC, mnemonic: "addc", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::SUBC, mnemonic: "subc", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::MULC, mnemonic: "mulc", operands: THREE_REGISTERS },
+    OpcodeInfo { opcode: Opcode::GETREM, mnemonic: "getrem", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::EQI, mnemonic: "eqi", operands: REGISTER_AND_CONSTANT },
+    OpcodeInfo { opcode: Opcode::NEQI, mnemonic: "neqi", operands: REGISTER_AND_CONSTANT },
+    OpcodeInfo { opcode: Opcode::GTI, mnemonic: "gti", operands: REGISTER_AND_CONSTANT },
+    OpcodeInfo { opcode: Opcode::LTI, mnemonic: "lti", operands: REGISTER_AND_CONSTANT },
+    OpcodeInfo { opcode: Opcode::GEQI, mnemonic: "geqi", operands: REGISTER_AND_CONSTANT },
+    OpcodeInfo { opcode: Opcode::LEQI, mnemonic: "leqi", operands: REGISTER_AND_CONSTANT },
+    OpcodeInfo { opcode: Opcode::SETCMP, mnemonic: "setcmp", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::SETNCMP, mnemonic: "setncmp", operands: ONE_REGISTER },
+    OpcodeInfo { opcode: Opcode::BEQ, mnemonic: "beq", operands: BRANCH },
+    OpcodeInfo { opcode: Opcode::BNE, mnemonic: "bne", operands: BRANCH },
+    OpcodeInfo { opcode: Opcode::BGT, mnemonic: "bgt", operands: BRANCH },
+    OpcodeInfo { opcode: Opcode::BLT, mnemonic: "blt", operands: BRANCH },
+    OpcodeInfo { opcode: Opcode::BGE, mnemonic: "bge", operands: BRANCH },
+    OpcodeInfo { opcode: Opcode::BLE, mnemonic: "ble", operands: BRANCH },
+    OpcodeInfo { opcode: Opcode::LA, mnemonic: "la", operands: &[Register, Immediate24] },
+    OpcodeInfo { opcode: Opcode::LOADR8, mnemonic: "loadr8", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LOADR16, mnemonic: "loadr16", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::LOADR32, mnemonic: "loadr32", operands: TWO_REGISTERS },
+    OpcodeInfo { opcode: Opcode::IGL, mnemonic: "igl", operands: NO_OPERANDS },
+];
+
+impl OpcodeInfo {
+    /// Number of bytes the instruction occupies: the opcode byte and its
+    /// operands, padded to a multiple of `INSTRUCTION_LENGTH`.
+    pub fn length(&self) -> usize {
+        let width: usize = 1 + self.operands.iter().map(OperandKind::width).sum::<usize>();
+        width.next_multiple_of(INSTRUCTION_LENGTH)
+    }
+}
+
+impl Opcode {
+    pub fn info(&self) -> &'static OpcodeInfo {
+        OPCODE_TABLE
+            .iter()
+            .find(|info| info.opcode == *self)
+            .expect("every opcode has an entry in OPCODE_TABLE")
+    }
+
+    /// Looks up an opcode by its assembler mnemonic, ignoring case. IGL's
+    /// mnemonic only labels illegal bytes in listings, so it is not found.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODE_TABLE
+            .iter()
+            .filter(|info| info.opcode != Opcode::IGL)
+            .find(|info| info.mnemonic.eq_ignore_ascii_case(mnemonic))
+            .map(|info| info.opcode)
+    }
+}
+
 impl From<u8> for Opcode {
     fn from(byte: u8) -> Self {
         match byte {
-            0 => return Opcode::HLT,
-            1 => return Opcode::LOAD,
-            2 => return Opcode::ADD,
-            3 => return Opcode::SUB,
-            4 => return Opcode::MUL,
-            5 => return Opcode::DIV,
-            6 => return Opcode::JMP,
-            7 => return Opcode::JMPF,
-            8 => return Opcode::JMPB,
-            9 => return Opcode::EQ,
-            10 => return Opcode::NEQ,
-            11 => return Opcode::JEQ,
-            12 => return Opcode::GEQ,
-            13 => return Opcode::LEQ,
-            14 => return Opcode::GT,
-            15 => return Opcode::LT,
-            16 => return Opcode::JNEQ,
-            _ => return Opcode::IGL
+            0 => Opcode::HLT,
+            1 => Opcode::LOAD,
+            2 => Opcode::ADD,
+            3 => Opcode::SUB,
+            4 => Opcode::MUL,
+            5 => Opcode::DIV,
+            6 => Opcode::JMP,
+            7 => Opcode::JMPF,
+            8 => Opcode::JMPB,
+            9 => Opcode::EQ,
+            10 => Opcode::NEQ,
+            11 => Opcode::JEQ,
+            12 => Opcode::GEQ,
+            13 => Opcode::LEQ,
+            14 => Opcode::GT,
+            15 => Opcode::LT,
+            16 => Opcode::JNEQ,
+            17 => Opcode::PUSH,
+            18 => Opcode::POP,
+            19 => Opcode::CALL,
+            20 => Opcode::RET,
+            21 => Opcode::ALOC,
+            22 => Opcode::LOADM8,
+            23 => Opcode::LOADM16,
+            24 => Opcode::LOADM32,
+            25 => Opcode::STOREM8,
+            26 => Opcode::STOREM16,
+            27 => Opcode::STOREM32,
+            28 => Opcode::LOADHI,
+            29 => Opcode::LOADF64,
+            30 => Opcode::ADDF64,
+            31 => Opcode::SUBF64,
+            32 => Opcode::MULF64,
+            33 => Opcode::DIVF64,
+            34 => Opcode::EQF64,
+            35 => Opcode::NEQF64,
+            36 => Opcode::GTF64,
+            37 => Opcode::LTF64,
+            38 => Opcode::GEQF64,
+            39 => Opcode::LEQF64,
+            40 => Opcode::AND,
+            41 => Opcode::OR,
+            42 => Opcode::XOR,
+            43 => Opcode::NOT,
+            44 => Opcode::SHL,
+            45 => Opcode::SHR,
+            46 => Opcode::SAR,
+            47 => Opcode::MOD,
+            48 => Opcode::ADDW,
+            49 => Opcode::SUBW,
+            50 => Opcode::MULW,
+            51 => Opcode::ADDC,
+            52 => Opcode::SUBC,
+            53 => Opcode::MULC,
+            54 => Opcode::GETREM,
+            55 => Opcode::EQI,
+            56 => Opcode::NEQI,
+            57 => Opcode::GTI,
+            58 => Opcode::LTI,
+            59 => Opcode::GEQI,
+            60 => Opcode::LEQI,
+            61 => Opcode::SETCMP,
+            62 => Opcode::SETNCMP,
+            63 => Opcode::BEQ,
+            64 => Opcode::BNE,
+            65 => Opcode::BGT,
+            66 => Opcode::BLT,
+            67 => Opcode::BGE,
+            68 => Opcode::BLE,
+            69 => Opcode::LA,
+            70 => Opcode::LOADR8,
+            71 => Opcode::LOADR16,
+            72 => Opcode::LOADR32,
+            _ => Opcode::IGL
         }
     }
 }
@@ -70,8 +327,26 @@ mod tests {
     }
 
     #[test]
-    fn test_create_instruction() {
-        let instruction = Instruction::new(Opcode::HLT);
-        assert_eq!(instruction.opcode, Opcode::HLT);
+    fn test_opcode_table_round_trips_bytes() {
+        for info in OPCODE_TABLE {
+            assert_eq!(Opcode::from(info.opcode as u8), info.opcode);
+            assert!(info.length().is_multiple_of(INSTRUCTION_LENGTH));
+        }
+    }
+
+    #[test]
+    fn test_instruction_length() {
+        assert_eq!(Opcode::ADD.info().length(), 4);
+        assert_eq!(Opcode::JMP.info().length(), 4);
+        assert_eq!(Opcode::LOADF64.info().length(), 12);
+    }
+
+    #[test]
+    fn test_from_mnemonic() {
+        assert_eq!(Opcode::from_mnemonic("jmpf"), Some(Opcode::JMPF));
+        assert_eq!(Opcode::from_mnemonic("JNEQ"), Some(Opcode::JNEQ));
+        assert_eq!(Opcode::from_mnemonic("Load"), Some(Opcode::LOAD));
+        assert_eq!(Opcode::from_mnemonic("nope"), None);
+        assert_eq!(Opcode::from_mnemonic("igl"), None);
     }
 }
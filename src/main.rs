@@ -2,8 +2,18 @@ pub mod vm;
 pub mod instruction;
 pub mod repl;
 pub mod assembler;
+pub mod image;
+pub mod disassembler;
+pub mod verifier;
+pub mod debugger;
+pub mod tracer;
+pub mod profiler;
+pub mod fuel;
+pub mod snapshot;
+pub mod history;
+pub mod cli;
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(cli::main(&args));
 }
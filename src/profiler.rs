@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::assembler::source_map::SourceMap;
+use crate::disassembler::instruction_at;
+use crate::instruction::Opcode;
+
+/// Number of instructions listed in the hot-spot section of a report.
+pub const HOT_SPOTS: usize = 10;
+
+/// How often a conditional jump was and was not taken.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Execution counts collected by a `VM` with profiling enabled.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Profiler {
+    total: u64,
+    pc_counts: BTreeMap<usize, u64>,
+    opcode_counts: HashMap<Opcode, u64>,
+    branches: BTreeMap<usize, BranchCounts>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Counts one execution of the instruction at `pc`.
+    pub fn record(&mut self, pc: usize, opcode: Opcode) {
+        self.total += 1;
+        *self.pc_counts.entry(pc).or_default() += 1;
+        *self.opcode_counts.entry(opcode).or_default() += 1;
+    }
+
+    /// Counts whether the conditional jump at `pc` was taken.
+    pub fn record_branch(&mut self, pc: usize, taken: bool) {
+        let counts = self.branches.entry(pc).or_default();
+        match taken {
+            true => counts.taken += 1,
+            false => counts.not_taken += 1,
+        }
+    }
+
+    /// Total number of instructions executed.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn count_at(&self, pc: usize) -> u64 {
+        self.pc_counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcode_counts.get(&opcode).copied().unwrap_or(0)
+    }
+
+    pub fn branch_counts(&self, pc: usize) -> Option<BranchCounts> {
+        self.branches.get(&pc).copied()
+    }
+
+    /// Renders the most executed instructions, the opcode histogram and the
+    /// conditional jumps. When `source` is given, hot spots show the line
+    /// they were assembled from.
+    pub fn report(&self, program: &[u8], source: Option<(&SourceMap, &str)>) -> String {
+        let mut text = format!("executed {} instructions\n\nhot spots:\n", self.total);
+        let mut hot: Vec<(&usize, &u64)> = self.pc_counts.iter().collect();
+        hot.sort_by(|(a_pc, a), (b_pc, b)| b.cmp(a).then(a_pc.cmp(b_pc)));
+        for (pc, count) in hot.into_iter().take(HOT_SPOTS) {
+            let mut line = format!("{count:>10}  {:>5.1}%  {pc:04}  {:<20}", self.share(*count), describe(program, *pc));
+            if let Some((source_map, source)) = source {
+                if let Some(number) = source_map.line_at(*pc as u32) {
+                    let written = source.lines().nth(number - 1).unwrap_or("").trim();
+                    line += &format!("  line {number}: {written}");
+                }
+            }
+            text += line.trim_end();
+            text += "\n";
+        }
+
+        text += "\nopcodes:\n";
+        let mut opcodes: Vec<(&Opcode, &u64)> = self.opcode_counts.iter().collect();
+        opcodes.sort_by(|(a_opcode, a), (b_opcode, b)| b.cmp(a).then((**a_opcode as u8).cmp(&(**b_opcode as u8))));
+        for (opcode, count) in opcodes {
+            text += &format!("{count:>10}  {:>5.1}%  {}\n", self.share(*count), opcode.info().mnemonic);
+        }
+
+        if !self.branches.is_empty() {
+            text += "\nbranches:\n";
+            for (pc, counts) in &self.branches {
+                text += &format!(
+                    "{pc:04}  {:<20}  taken {}, not taken {}\n",
+                    describe(program, *pc),
+                    counts.taken,
+                    counts.not_taken
+                );
+            }
+        }
+        text
+    }
+
+    fn share(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total.max(1) as f64
+    }
+}
+
+fn describe(program: &[u8], pc: usize) -> String {
+    instruction_at(program, pc).map_or_else(String::new, |instruction| instruction.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_with_source_map;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_report() {
+        let source = "load $1 #1\nload $2 #2\nloop: add $0 $1 $0\neq $0 $2\njneq $3\nhlt";
+        let (image, source_map) = assemble_with_source_map(source).unwrap();
+        let mut vm = VM::new();
+        vm.load(&image.to_bytes()).unwrap();
+        vm.registers[3] = 8;
+        vm.enable_profiling();
+        vm.run().unwrap();
+
+        let report = vm.profiler().unwrap().report(&image.code, Some((&source_map, source)));
+        assert!(report.starts_with("executed 9 instructions\n\nhot spots:\n"));
+        assert!(report.contains("         2   22.2%  0008  add $0 $1 $0          line 3: loop: add $0 $1 $0\n"));
+        assert!(report.contains("         2   22.2%  jneq\n"));
+        assert!(report.contains("branches:\n0016  jneq $3               taken 1, not taken 1\n"));
+        assert!(!vm.profiler().unwrap().report(&image.code, None).contains("line"));
+    }
+}
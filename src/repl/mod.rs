@@ -1,22 +1,35 @@
-use std::{io, io::Write, num::ParseIntError};
-use nom::types::CompleteStr;
+use std::{fs, io, io::Write, num::ParseIntError, path::Path};
 
-use crate::{vm::VM, assembler::instruction_parsers::program};
+use crate::{cli, disassembler, vm::{StepOutcome, VM}, assembler::instruction_parsers::program};
+use crate::debugger::{Debugger, StopReason, Watchpoint};
+use crate::history::DEFAULT_HISTORY_LIMIT;
+use crate::snapshot::Snapshot;
+
+/// Most instructions a single line may run before the REPL stops it.
+const LINE_STEP_LIMIT: usize = 100_000;
 
 pub struct REPL {
     command_buffer: Vec<String>,
     vm: VM,
+    debugger: Debugger,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
     pub fn new() -> REPL {
         REPL {
             command_buffer: vec![],
-            vm: VM::new()
+            vm: VM::new(),
+            debugger: Debugger::new(),
         }
     }
 
-    pub fn run(&mut self) -> () {
+    pub fn run(&mut self) {
         println!("Welcome! Write your Kurals!");
         loop {
             let mut buffer = String::new();
@@ -42,24 +55,175 @@ impl REPL {
                 }
                 ".registers" => {
                     println!("{:?}", self.vm.registers);
+                    println!("{:?}", self.vm.float_registers);
                 }
-                _ => {
-                    let (_, parsed_program) = program(CompleteStr(buffer)).unwrap();
-                    println!("{:#?}", parsed_program.to_bytes());
-                    for byte in parsed_program.to_bytes() {
-                        self.vm.add_byte(byte);
-                    }
-                    // match self.parse_hex(buffer) {
-                    //     Ok(bytes) => {
-                    //         for byte in bytes {
-                    //             self.vm.add_byte(byte);
-                    //         }
-                    //     },
-                    //     Err(err) => panic!("Failed to parse the hex input: {buffer}. Error: {err}")
-                    // };
-                    self.vm.run_once();
+                ".stack" => {
+                    println!("values: {:?}", self.vm.stack());
+                    println!("return addresses: {:?}", self.vm.call_stack());
+                }
+                ".heap" => {
+                    println!("{:?}", self.vm.heap());
+                }
+                ".disasm" => {
+                    print!("{}", disassembler::listing(self.vm.program(), None));
+                }
+                _ if buffer.starts_with('.') => self.debug_command(buffer),
+                _ => self.execute_source(buffer),
+            }
+        }
+    }
+
+    /// Assembles a line of source, appends it to the program and runs the VM
+    /// on from where it stopped.
+    fn execute_source(&mut self, buffer: &str) {
+        // assemble at the end of the program so labels resolve to where the
+        // line is appended
+        let base = self.vm.program().len();
+        let bytes = match program(buffer).and_then(|parsed_program| parsed_program.to_bytes_at(base)) {
+            Ok(bytes) => bytes,
+            Err(errors) => {
+                for error in errors {
+                    println!("Error: {}", error.with_source(buffer));
+                }
+                return;
+            }
+        };
+        let end = base + bytes.len();
+        for byte in bytes {
+            self.vm.add_byte(byte);
+        }
+        if self.vm.pc() != base {
+            println!("Error: the VM is paused at offset {}, so the line was added but not run", self.vm.pc());
+            return;
+        }
+        // run only what was just added, and give up on lines that loop
+        for _ in 0..LINE_STEP_LIMIT {
+            if !(base..end).contains(&self.vm.pc()) {
+                return;
+            }
+            match self.vm.run_once() {
+                Ok(StepOutcome::Continue) => (),
+                Ok(StepOutcome::Halted) => return println!("HLT Encountered!"),
+                Ok(_) => return,
+                Err(err) => {
+                    println!("Error: {err}");
+                    return self.discard_line(base);
+                }
+            }
+        }
+        println!("Error: stopped after {LINE_STEP_LIMIT} instructions without leaving the line");
+        self.discard_line(base);
+    }
+
+    /// Drops a line that did not run to completion, so the next one starts
+    /// where it did. Whatever its earlier instructions changed is kept.
+    fn discard_line(&mut self, base: usize) {
+        self.vm.truncate_program(base);
+        println!("The line was discarded");
+    }
+
+    /// Handles the debugger commands, which take arguments.
+    fn debug_command(&mut self, buffer: &str) {
+        let mut words = buffer.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        let result = match (command, argument) {
+            (".load", Some(path)) => cli::load_image(Path::new(path)).and_then(|image| {
+                self.vm = VM::new();
+                self.vm.load(&image.to_bytes()).map_err(|err| err.to_string())?;
+                self.vm.enable_history(DEFAULT_HISTORY_LIMIT);
+                self.debugger.set_symbols(image.symbols.unwrap_or_default());
+                println!("Loaded {} bytes of code", image.code.len());
+                Ok(())
+            }),
+            (".save", Some(path)) => fs::write(path, self.vm.snapshot().to_bytes())
+                .map_err(|err| format!("cannot write {path}: {err}")),
+            (".restore", Some(path)) => fs::read(path)
+                .map_err(|err| format!("cannot read {path}: {err}"))
+                .and_then(|bytes| Snapshot::from_bytes(&bytes).map_err(|err| format!("{path}: {err}")))
+                .map(|snapshot| {
+                    self.vm = VM::from_snapshot(snapshot);
+                    self.print_current_instruction();
+                }),
+            (".break", Some(location)) => self.debugger.add_breakpoint(location).map(|offset| {
+                println!("Breakpoint at {offset}");
+            }),
+            (".delete", Some(location)) => self.debugger.remove_breakpoint(location).map(|offset| {
+                println!("Removed breakpoint at {offset}");
+            }),
+            (".breakpoints", None) => {
+                for offset in self.debugger.breakpoints() {
+                    println!("{offset}");
                 }
+                Ok(())
             }
+            (".watch", Some(target)) => Self::parse_watchpoint(target).map(|watchpoint| {
+                self.debugger.add_watchpoint(watchpoint);
+                println!("Watching {watchpoint}");
+            }),
+            (".unwatch", Some(target)) => Self::parse_watchpoint(target).and_then(|watchpoint| {
+                match self.debugger.remove_watchpoint(watchpoint) {
+                    true => Ok(()),
+                    false => Err(format!("{watchpoint} is not watched")),
+                }
+            }),
+            (".step", None) => self.report(|debugger, vm| debugger.step(vm)),
+            (".next", None) => self.report(|debugger, vm| debugger.step_over(vm)),
+            (".continue", None) => self.report(|debugger, vm| debugger.continue_execution(vm)),
+            (".back", None) => self.debugger.step_back(&mut self.vm).map(|_| self.print_current_instruction()),
+            (".goto", Some(count)) => count
+                .parse::<u64>()
+                .map_err(|_| format!("`{count}` is not an instruction count"))
+                .and_then(|count| self.report(|debugger, vm| debugger.goto(vm, count))),
+            (".where", None) => {
+                self.print_current_instruction();
+                Ok(())
+            }
+            (".data" | ".code", _) => Err(
+                "the REPL runs code one line at a time and has no data section; put data in a file and `.load` it"
+                    .to_string(),
+            ),
+            _ => Err(format!("Unknown command: {buffer}")),
+        };
+        if let Err(err) = result {
+            println!("Error: {err}");
+        }
+    }
+
+    fn parse_watchpoint(target: &str) -> Result<Watchpoint, String> {
+        if target == "cmp" {
+            return Ok(Watchpoint::Comparison);
+        }
+        target
+            .strip_prefix('$')
+            .and_then(|register| register.parse::<usize>().ok())
+            .filter(|register| *register < 32)
+            .map(Watchpoint::Register)
+            .ok_or_else(|| format!("`{target}` is neither a register nor `cmp`"))
+    }
+
+    fn report<F, E>(&mut self, action: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Debugger, &mut VM) -> Result<StopReason, E>,
+        E: ToString,
+    {
+        match action(&mut self.debugger, &mut self.vm).map_err(|err| err.to_string())? {
+            StopReason::Stepped => (),
+            StopReason::Breakpoint { offset } => println!("Stopped at breakpoint {offset}"),
+            StopReason::Watchpoint { watchpoint, old, new } => println!("{watchpoint} changed from {old} to {new}"),
+            StopReason::Finished(StepOutcome::Halted) => println!("HLT Encountered!"),
+            StopReason::Finished(StepOutcome::OutOfFuel) => println!("Out of fuel"),
+            StopReason::Finished(_) => println!("End of program"),
+        }
+        self.print_current_instruction();
+        Ok(())
+    }
+
+    fn print_current_instruction(&self) {
+        let count = self.vm.instruction_count().map_or_else(String::new, |count| format!("[{count}] "));
+        match self.debugger.current_instruction(&self.vm) {
+            Some(instruction) => println!("{count}{:04}  {instruction}", instruction.offset),
+            None => println!("{count}{:04}  <end of program>", self.vm.pc()),
         }
     }
 
@@ -75,4 +239,53 @@ impl REPL {
         }
         Ok(parsed_instructions)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute_source_after_hlt() {
+        let mut repl = REPL::new();
+        repl.execute_source("hlt");
+        repl.execute_source("load $1 #5");
+        assert_eq!(repl.vm.registers[1], 5);
+        assert_eq!(repl.vm.program().len(), 8);
+    }
+
+    #[test]
+    fn test_execute_source_stops_looping_lines() {
+        let mut repl = REPL::new();
+        repl.execute_source("loop: jmp @loop");
+        assert_eq!(repl.vm.program().len(), 0);
+        repl.execute_source("load $1 #3");
+        assert_eq!(repl.vm.registers[1], 3);
+    }
+
+    #[test]
+    fn test_execute_source_recovers_from_faults() {
+        let mut repl = REPL::new();
+        repl.execute_source("load $1 #3");
+        repl.execute_source("div $1 $0 $2");
+        assert_eq!(repl.vm.program().len(), 4);
+        repl.execute_source("load $2 #4");
+        assert_eq!(repl.vm.registers[1..3], [3, 4]);
+    }
+
+    #[test]
+    fn test_execute_source_runs_wide_loads_in_full() {
+        let mut repl = REPL::new();
+        repl.execute_source("load $2 #-70000");
+        assert_eq!(repl.vm.registers[2], -70000);
+        assert_eq!(repl.vm.pc(), 8);
+    }
+
+    #[test]
+    fn test_execute_source_resolves_labels_after_existing_code() {
+        let mut repl = REPL::new();
+        repl.execute_source("load $1 #1");
+        repl.execute_source("here: load $2 @here");
+        assert_eq!(repl.vm.registers[2], 4);
+    }
+}
@@ -0,0 +1,362 @@
+//! A paused VM's complete state, saved so it can be restored into a fresh VM.
+//!
+//! All multi-byte fields are big-endian, like images:
+//!
+//! | offset | size | field                                                   |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | 4    | magic, `PSNP`                                           |
+//! | 4      | 2    | format version                                          |
+//! | 6      | 2    | flags, see below                                        |
+//! | 8      | 4    | program counter                                         |
+//! | 12     | 4    | signed remainder of the last DIV                        |
+//! | 16     | 8    | fuel left, meaningful only when bit 1 is set            |
+//! | 24     | 8    | fuel used                                               |
+//! | 32     | 128  | the 32 registers                                        |
+//! | 160    | 256  | the 32 float registers                                  |
+//! | 416    | 4    | program length in bytes                                 |
+//! | 420    | 4    | read-only data length in bytes                          |
+//! | 424    | 4    | value stack length in entries                           |
+//! | 428    | 4    | call stack length in entries                            |
+//! | 432    | 4    | heap length in bytes                                    |
+//! | 436    | ...  | program, read-only data, stack, call stack, then heap   |
+//!
+//! Flag bit 0 holds the comparison result, bit 1 is set when fuel is limited,
+//! bit 2 holds the overflow flag and bits 8 and 9 the overflow mode: 0 for
+//! trap, 1 for wrap and 2 for saturate.
+//!
+//! Stack entries are 4-byte values and call stack entries 4-byte return
+//! addresses, both listed from the bottom up.
+//!
+//! Versions 1 and 2 are still read. Version 1 has no float registers, so the
+//! fields from offset 160 on sit 256 bytes earlier and the float registers
+//! restore as zero. Neither version has the overflow flag or mode bits; they
+//! restore with the flag clear in wrap mode, which is how arithmetic behaved
+//! when they were written.
+use std::{error, fmt};
+
+use crate::image::{read_u16, read_u32};
+use crate::vm::{OverflowMode, CALL_STACK_LIMIT, HEAP_LIMIT, STACK_LIMIT};
+
+pub const MAGIC: [u8; 4] = *b"PSNP";
+pub const VERSION: u16 = 3;
+pub const HEADER_LENGTH: usize = 436;
+/// Bytes taken by the float registers, which version 1 lacks.
+const FLOAT_REGISTERS_LENGTH: usize = 256;
+
+const FLAG_COMPARISON: u16 = 1;
+const FLAG_FUEL: u16 = 1 << 1;
+const FLAG_OVERFLOW: u16 = 1 << 2;
+const OVERFLOW_MODE_SHIFT: u16 = 8;
+const OVERFLOW_MODE_MASK: u16 = 0b11 << OVERFLOW_MODE_SHIFT;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    TooShort { length: usize },
+    BadMagic,
+    UnsupportedVersion { version: u16 },
+    /// The section lengths in the header do not add up to the snapshot length.
+    LengthMismatch { expected: usize, actual: usize },
+    /// The program counter or a return address lies outside the program.
+    AddressOutOfBounds { address: u32 },
+    /// A stack or the heap is larger than the VM allows.
+    LimitExceeded,
+    UnknownOverflowMode { mode: u16 },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::TooShort { length } => {
+                write!(f, "snapshot is {length} bytes, shorter than its header")
+            }
+            SnapshotError::BadMagic => write!(f, "not a porul snapshot (bad magic number)"),
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(f, "unsupported snapshot version {version}, expected 1 to {VERSION}")
+            }
+            SnapshotError::LengthMismatch { expected, actual } => {
+                write!(f, "header describes {expected} bytes but the snapshot is {actual} bytes")
+            }
+            SnapshotError::AddressOutOfBounds { address } => write!(f, "address {address} is outside the program"),
+            SnapshotError::LimitExceeded => write!(f, "a stack or the heap exceeds the VM's limits"),
+            SnapshotError::UnknownOverflowMode { mode } => write!(f, "unknown overflow mode {mode}"),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+/// Everything a `VM` needs to carry on executing where it was paused. The
+/// profiler and cost table are configuration rather than state, and are not
+/// saved.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
+    pub pc: usize,
+    pub program: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub remainder: i32,
+    pub comparison_result: bool,
+    pub overflow: bool,
+    pub overflow_mode: OverflowMode,
+    pub stack: Vec<i32>,
+    pub call_stack: Vec<usize>,
+    pub heap: Vec<u8>,
+    pub fuel: Option<u64>,
+    pub fuel_used: u64,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            registers: [0; 32],
+            float_registers: [0.0; 32],
+            pc: 0,
+            program: vec![],
+            ro_data: vec![],
+            remainder: 0,
+            comparison_result: false,
+            overflow: false,
+            overflow_mode: OverflowMode::default(),
+            stack: vec![],
+            call_stack: vec![],
+            heap: vec![],
+            fuel: None,
+            fuel_used: 0,
+        }
+    }
+}
+
+impl Snapshot {
+    /// Returns true when `bytes` starts with the snapshot magic number.
+    pub fn is_snapshot(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.comparison_result {
+            flags |= FLAG_COMPARISON;
+        }
+        if self.fuel.is_some() {
+            flags |= FLAG_FUEL;
+        }
+        if self.overflow {
+            flags |= FLAG_OVERFLOW;
+        }
+        let mode: u16 = match self.overflow_mode {
+            OverflowMode::Trap => 0,
+            OverflowMode::Wrap => 1,
+            OverflowMode::Saturate => 2,
+        };
+        flags |= mode << OVERFLOW_MODE_SHIFT;
+
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.program.len() + self.ro_data.len() + self.heap.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&flags.to_be_bytes());
+        bytes.extend_from_slice(&(self.pc as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.remainder.to_be_bytes());
+        bytes.extend_from_slice(&self.fuel.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&self.fuel_used.to_be_bytes());
+        for register in self.registers {
+            bytes.extend_from_slice(&register.to_be_bytes());
+        }
+        for register in self.float_registers {
+            bytes.extend_from_slice(&register.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.program.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.ro_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.stack.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.call_stack.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.heap.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.program);
+        bytes.extend_from_slice(&self.ro_data);
+        for value in &self.stack {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        for return_address in &self.call_stack {
+            bytes.extend_from_slice(&(*return_address as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.heap);
+        bytes
+    }
+
+    /// Parses and validates a snapshot.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if bytes.len() < 6 {
+            return Err(SnapshotError::TooShort { length: bytes.len() });
+        }
+        if !Snapshot::is_snapshot(bytes) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = read_u16(bytes, 4);
+        // the float registers, when there are any, sit just before the lengths
+        let float_registers_length = match version {
+            1 => 0,
+            2 | VERSION => FLOAT_REGISTERS_LENGTH,
+            _ => return Err(SnapshotError::UnsupportedVersion { version }),
+        };
+        let lengths = 160 + float_registers_length;
+        let header_length = lengths + 20;
+        if bytes.len() < header_length {
+            return Err(SnapshotError::TooShort { length: bytes.len() });
+        }
+        let flags = read_u16(bytes, 6);
+        let overflow_mode = match (flags & OVERFLOW_MODE_MASK) >> OVERFLOW_MODE_SHIFT {
+            _ if version < VERSION => OverflowMode::Wrap,
+            0 => OverflowMode::Trap,
+            1 => OverflowMode::Wrap,
+            2 => OverflowMode::Saturate,
+            mode => return Err(SnapshotError::UnknownOverflowMode { mode }),
+        };
+        let program_length = read_u32(bytes, lengths) as usize;
+        let ro_data_length = read_u32(bytes, lengths + 4) as usize;
+        let stack_length = read_u32(bytes, lengths + 8) as usize;
+        let call_stack_length = read_u32(bytes, lengths + 12) as usize;
+        let heap_length = read_u32(bytes, lengths + 16) as usize;
+        if stack_length > STACK_LIMIT || call_stack_length > CALL_STACK_LIMIT || heap_length > HEAP_LIMIT {
+            return Err(SnapshotError::LimitExceeded);
+        }
+
+        let expected = header_length + program_length + ro_data_length + 4 * stack_length + 4 * call_stack_length + heap_length;
+        if expected != bytes.len() {
+            return Err(SnapshotError::LengthMismatch { expected, actual: bytes.len() });
+        }
+
+        let pc = read_u32(bytes, 8);
+        if pc as usize > program_length {
+            return Err(SnapshotError::AddressOutOfBounds { address: pc });
+        }
+        let mut registers = [0; 32];
+        for (index, register) in registers.iter_mut().enumerate() {
+            *register = read_u32(bytes, 32 + 4 * index) as i32;
+        }
+        let mut float_registers = [0.0; 32];
+        if float_registers_length > 0 {
+            for (index, register) in float_registers.iter_mut().enumerate() {
+                *register = f64::from_bits(read_u64(bytes, 160 + 8 * index));
+            }
+        }
+
+        let ro_data_start = header_length + program_length;
+        let stack_start = ro_data_start + ro_data_length;
+        let call_stack_start = stack_start + 4 * stack_length;
+        let heap_start = call_stack_start + 4 * call_stack_length;
+        let stack = (0..stack_length).map(|index| read_u32(bytes, stack_start + 4 * index) as i32).collect();
+        let mut call_stack = Vec::with_capacity(call_stack_length);
+        for index in 0..call_stack_length {
+            let address = read_u32(bytes, call_stack_start + 4 * index);
+            if address as usize > program_length {
+                return Err(SnapshotError::AddressOutOfBounds { address });
+            }
+            call_stack.push(address as usize);
+        }
+
+        Ok(Snapshot {
+            registers,
+            float_registers,
+            pc: pc as usize,
+            program: bytes[header_length..ro_data_start].to_vec(),
+            ro_data: bytes[ro_data_start..stack_start].to_vec(),
+            remainder: read_u32(bytes, 12) as i32,
+            comparison_result: flags & FLAG_COMPARISON != 0,
+            overflow: flags & FLAG_OVERFLOW != 0,
+            overflow_mode,
+            stack,
+            call_stack,
+            heap: bytes[heap_start..].to_vec(),
+            fuel: (flags & FLAG_FUEL != 0).then(|| read_u64(bytes, 16)),
+            fuel_used: read_u64(bytes, 24),
+        })
+    }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    (read_u32(bytes, offset) as u64) << 32 | read_u32(bytes, offset + 4) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_snapshot() -> Snapshot {
+        let mut registers = [0; 32];
+        registers[0] = -7;
+        registers[31] = 1 << 20;
+        let mut float_registers = [0.0; 32];
+        float_registers[2] = -1.25;
+        Snapshot {
+            registers,
+            float_registers,
+            pc: 4,
+            program: vec![1, 0, 0, 5, 0, 0, 0, 0],
+            ro_data: vec![9],
+            remainder: -3,
+            comparison_result: true,
+            overflow: true,
+            overflow_mode: OverflowMode::Saturate,
+            stack: vec![1, -2],
+            call_stack: vec![8],
+            heap: vec![0xAB; 5],
+            fuel: Some(40),
+            fuel_used: 1 << 40,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trips() {
+        let snapshot = test_snapshot();
+        let bytes = snapshot.to_bytes();
+        assert!(Snapshot::is_snapshot(&bytes));
+        assert_eq!(bytes.len(), HEADER_LENGTH + 8 + 1 + 8 + 4 + 5);
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot));
+
+        let unlimited = Snapshot { fuel: None, ..Snapshot::default() };
+        assert_eq!(Snapshot::from_bytes(&unlimited.to_bytes()), Ok(unlimited));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_malformed_bytes() {
+        let bytes = test_snapshot().to_bytes();
+        assert_eq!(Snapshot::from_bytes(&bytes[..10]), Err(SnapshotError::TooShort { length: 10 }));
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Snapshot::from_bytes(&bad), Err(SnapshotError::BadMagic));
+
+        let mut bad = bytes.clone();
+        bad[5] = 9;
+        assert_eq!(Snapshot::from_bytes(&bad), Err(SnapshotError::UnsupportedVersion { version: 9 }));
+
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::LengthMismatch { expected: bytes.len(), actual: bytes.len() - 1 })
+        );
+
+        let mut bad = bytes.clone();
+        bad[6] = 3;
+        assert_eq!(Snapshot::from_bytes(&bad), Err(SnapshotError::UnknownOverflowMode { mode: 3 }));
+
+        let mut bad = bytes.clone();
+        bad[11] = 12;
+        assert_eq!(Snapshot::from_bytes(&bad), Err(SnapshotError::AddressOutOfBounds { address: 12 }));
+    }
+
+    #[test]
+    fn test_snapshot_reads_older_versions() {
+        let snapshot = Snapshot { overflow: false, overflow_mode: OverflowMode::Wrap, ..test_snapshot() };
+        let mut version_2 = snapshot.to_bytes();
+        version_2[5] = 2;
+        // older versions never set the mode bits, which read as trap in version 3
+        version_2[6] = 0;
+        assert_eq!(Snapshot::from_bytes(&version_2), Ok(snapshot.clone()));
+
+        let mut version_1 = version_2.clone();
+        version_1[5] = 1;
+        version_1.drain(160..160 + FLOAT_REGISTERS_LENGTH);
+        let without_floats = Snapshot { float_registers: [0.0; 32], ..snapshot };
+        assert_eq!(Snapshot::from_bytes(&version_1), Ok(without_floats));
+        assert_eq!(Snapshot::from_bytes(&version_1[..179]), Err(SnapshotError::TooShort { length: 179 }));
+    }
+}
@@ -0,0 +1,264 @@
+use std::{error, fmt, io, io::Write};
+
+use crate::disassembler::instruction_at;
+use crate::instruction::Opcode;
+use crate::vm::{StepOutcome, VmError, VM};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+    /// One aligned line per instruction, for reading.
+    Text,
+    /// One JSON object per line, for tools.
+    JsonLines,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" | "jsonl" => Some(TraceFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// A register whose value changed while executing one instruction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RegisterWrite {
+    pub register: usize,
+    pub old: i32,
+    pub new: i32,
+}
+
+/// A float register whose value changed while executing one instruction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FloatRegisterWrite {
+    pub register: usize,
+    pub old: f64,
+    pub new: f64,
+}
+
+/// What a single executed instruction did.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub opcode: Opcode,
+    /// The operands as written in assembler syntax, e.g. `$1` or `#500`.
+    pub operands: Vec<String>,
+    pub register_writes: Vec<RegisterWrite>,
+    pub float_register_writes: Vec<FloatRegisterWrite>,
+    /// The old and new comparison flag, when the instruction changed it.
+    pub comparison: Option<(bool, bool)>,
+}
+
+impl TraceEntry {
+    fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut instruction = self.opcode.info().mnemonic.to_string();
+        for operand in &self.operands {
+            instruction += &format!(" {operand}");
+        }
+        let mut line = format!("{:04}  {instruction:<20}", self.pc);
+        for write in &self.register_writes {
+            line += &format!("  ${}: {} -> {}", write.register, write.old, write.new);
+        }
+        for write in &self.float_register_writes {
+            line += &format!("  $f{}: {:?} -> {:?}", write.register, write.old, write.new);
+        }
+        if let Some((old, new)) = self.comparison {
+            line += &format!("  cmp: {old} -> {new}");
+        }
+        writeln!(out, "{}", line.trim_end())
+    }
+
+    fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let operands: Vec<String> = self.operands.iter().map(|operand| format!("\"{operand}\"")).collect();
+        let writes: Vec<String> = self
+            .register_writes
+            .iter()
+            .map(|write| format!("{{\"register\":{},\"old\":{},\"new\":{}}}", write.register, write.old, write.new))
+            .collect();
+        let float_writes: Vec<String> = self
+            .float_register_writes
+            .iter()
+            .map(|write| {
+                format!("{{\"register\":{},\"old\":{},\"new\":{}}}", write.register, json_float(write.old), json_float(write.new))
+            })
+            .collect();
+        let comparison = match self.comparison {
+            Some((old, new)) => format!("{{\"old\":{old},\"new\":{new}}}"),
+            None => "null".to_string(),
+        };
+        writeln!(
+            out,
+            "{{\"pc\":{},\"opcode\":\"{:?}\",\"operands\":[{}],\"registers\":[{}],\"float_registers\":[{}],\"comparison\":{}}}",
+            self.pc,
+            self.opcode,
+            operands.join(","),
+            writes.join(","),
+            float_writes.join(","),
+            comparison
+        )
+    }
+}
+
+/// JSON has no infinities or NaN, so those are written as strings.
+fn json_float(value: f64) -> String {
+    match value.is_finite() {
+        true => format!("{value:?}"),
+        false => format!("\"{value:?}\""),
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Vm(VmError),
+    Io(io::Error),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Vm(err) => write!(f, "{err}"),
+            TraceError::Io(err) => write!(f, "cannot write trace: {err}"),
+        }
+    }
+}
+
+impl error::Error for TraceError {}
+
+/// Runs a `VM` one instruction at a time, logging a `TraceEntry` for each
+/// instruction that executes.
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, format: TraceFormat) -> Tracer<W> {
+        Tracer { out, format }
+    }
+
+    /// Executes a single instruction and logs it. Nothing is logged when the
+    /// program has already ended, runs out of fuel or the instruction faults.
+    pub fn step(&mut self, vm: &mut VM) -> Result<StepOutcome, TraceError> {
+        let Some(instruction) = instruction_at(vm.program(), vm.pc()) else {
+            return vm.run_once().map_err(TraceError::Vm);
+        };
+        let registers = vm.registers;
+        let float_registers = vm.float_registers;
+        let comparison = vm.comparison_result();
+        let outcome = vm.run_once().map_err(TraceError::Vm)?;
+        if outcome == StepOutcome::OutOfFuel {
+            return Ok(outcome);
+        }
+
+        let entry = TraceEntry {
+            pc: instruction.offset,
+            opcode: instruction.opcode,
+            operands: instruction.operands.iter().map(ToString::to_string).collect(),
+            register_writes: registers
+                .iter()
+                .zip(vm.registers)
+                .enumerate()
+                .filter(|(_, (old, new))| *old != new)
+                .map(|(register, (old, new))| RegisterWrite { register, old: *old, new })
+                .collect(),
+            float_register_writes: float_registers
+                .iter()
+                .zip(vm.float_registers)
+                .enumerate()
+                .filter(|(_, (old, new))| old.to_bits() != new.to_bits())
+                .map(|(register, (old, new))| FloatRegisterWrite { register, old: *old, new })
+                .collect(),
+            comparison: (comparison != vm.comparison_result()).then_some((comparison, vm.comparison_result())),
+        };
+        match self.format {
+            TraceFormat::Text => entry.write_text(&mut self.out),
+            TraceFormat::JsonLines => entry.write_json(&mut self.out),
+        }
+        .map_err(TraceError::Io)?;
+        Ok(outcome)
+    }
+
+    /// Executes instructions until the program halts, runs off its end or faults.
+    pub fn run(&mut self, vm: &mut VM) -> Result<StepOutcome, TraceError> {
+        loop {
+            match self.step(vm)? {
+                StepOutcome::Continue => (),
+                outcome => {
+                    self.out.flush().map_err(TraceError::Io)?;
+                    return Ok(outcome);
+                }
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn trace(source: &str, format: TraceFormat) -> String {
+        let mut vm = VM::new();
+        vm.load(&assemble(source).unwrap().to_bytes()).unwrap();
+        let mut tracer = Tracer::new(vec![], format);
+        assert_eq!(tracer.run(&mut vm).unwrap(), StepOutcome::Halted);
+        String::from_utf8(tracer.into_inner()).unwrap()
+    }
+
+    const PROGRAM: &str = "load $0 #5\nload $1 #5\neq $0 $1\nhlt";
+
+    #[test]
+    fn test_text_trace() {
+        assert_eq!(
+            trace(PROGRAM, TraceFormat::Text),
+            "0000  load $0 #5            $0: 0 -> 5\n\
+             0004  load $1 #5            $1: 0 -> 5\n\
+             0008  eq $0 $1              cmp: false -> true\n\
+             0012  hlt\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines_trace() {
+        let text = trace(PROGRAM, TraceFormat::JsonLines);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            r##"{"pc":0,"opcode":"LOAD","operands":["$0","#5"],"registers":[{"register":0,"old":0,"new":5}],"float_registers":[],"comparison":null}"##
+        );
+        assert_eq!(
+            lines[2],
+            r#"{"pc":8,"opcode":"EQ","operands":["$0","$1"],"registers":[],"float_registers":[],"comparison":{"old":false,"new":true}}"#
+        );
+    }
+
+    #[test]
+    fn test_trace_float_registers() {
+        let source = "loadf64 $f1 #0.5\ndivf64 $f1 $f0 $f2\nhlt";
+        assert_eq!(
+            trace(source, TraceFormat::Text).lines().nth(1),
+            Some("0012  divf64 $f1 $f0 $f2    $f2: 0.0 -> inf")
+        );
+        assert_eq!(
+            trace(source, TraceFormat::JsonLines).lines().next(),
+            Some(r##"{"pc":0,"opcode":"LOADF64","operands":["$f1","#0.5"],"registers":[],"float_registers":[{"register":1,"old":0.0,"new":0.5}],"comparison":null}"##)
+        );
+        assert!(trace(source, TraceFormat::JsonLines).contains(r#""new":"inf""#));
+    }
+
+    #[test]
+    fn test_trace_stops_at_fault() {
+        let mut vm = VM::new();
+        vm.load(&assemble("load $0 #1\ndiv $0 $1 $2\nhlt").unwrap().to_bytes()).unwrap();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Text);
+        assert!(matches!(tracer.run(&mut vm), Err(TraceError::Vm(VmError::DivisionByZero { pc: 4, .. }))));
+        assert_eq!(String::from_utf8(tracer.into_inner()).unwrap().lines().count(), 1);
+    }
+}
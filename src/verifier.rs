@@ -0,0 +1,161 @@
+use std::{collections::BTreeSet, fmt};
+
+use crate::assembler::Token;
+use crate::disassembler::{disassemble, DisassembledInstruction};
+use crate::instruction::Opcode;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiagnosticKind {
+    UnknownOpcode { byte: u8 },
+    InvalidRegister { register: u8 },
+    /// The program ends partway through an instruction.
+    TruncatedInstruction,
+    JumpOutOfBounds { target: i64 },
+    /// The jump lands inside an instruction rather than at its start.
+    MisalignedJump { target: i64 },
+    /// The last instruction can fall through past the end of the program.
+    MissingTerminator,
+}
+
+/// A problem found by `verify`, located at the offset of the offending instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub offset: usize,
+    pub kind: DiagnosticKind,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}: ", self.offset)?;
+        match &self.kind {
+            DiagnosticKind::UnknownOpcode { byte } => write!(f, "unknown opcode {byte:#04x}"),
+            DiagnosticKind::InvalidRegister { register } => write!(f, "register index {register} does not exist"),
+            DiagnosticKind::TruncatedInstruction => write!(f, "instruction is cut off by the end of the program"),
+            DiagnosticKind::JumpOutOfBounds { target } => write!(f, "jump target {target} is outside the program"),
+            DiagnosticKind::MisalignedJump { target } => write!(f, "jump target {target} is not the start of an instruction"),
+            DiagnosticKind::MissingTerminator => write!(f, "program does not end with HLT, RET or an unconditional jump"),
+        }
+    }
+}
+
+/// Checks `program` without running it and returns every problem found, in
+/// offset order. An empty result means the program is well formed.
+pub fn verify(program: &[u8]) -> Vec<Diagnostic> {
+    let instructions = disassemble(program);
+    let starts: BTreeSet<i64> = instructions.iter().map(|instruction| instruction.offset as i64).collect();
+    let mut diagnostics = vec![];
+
+    for instruction in &instructions {
+        let offset = instruction.offset;
+        if instruction.opcode == Opcode::IGL {
+            diagnostics.push(Diagnostic { offset, kind: DiagnosticKind::UnknownOpcode { byte: instruction.bytes[0] } });
+            continue;
+        }
+        if instruction.is_illegal() {
+            diagnostics.push(Diagnostic { offset, kind: DiagnosticKind::TruncatedInstruction });
+            continue;
+        }
+        for operand in &instruction.operands {
+            if let Token::Register { reg_number } | Token::FloatRegister { reg_number } = operand {
+                if *reg_number >= 32 {
+                    diagnostics.push(Diagnostic { offset, kind: DiagnosticKind::InvalidRegister { register: *reg_number } });
+                }
+            }
+        }
+        if let Some(target) = static_target(instruction) {
+            if target < 0 || target >= program.len() as i64 {
+                diagnostics.push(Diagnostic { offset, kind: DiagnosticKind::JumpOutOfBounds { target } });
+            } else if !starts.contains(&target) {
+                diagnostics.push(Diagnostic { offset, kind: DiagnosticKind::MisalignedJump { target } });
+            }
+        }
+    }
+
+    let terminated = instructions.last().is_some_and(|last| {
+        !last.is_illegal() && matches!(last.opcode, Opcode::HLT | Opcode::RET | Opcode::JMP | Opcode::JMPF | Opcode::JMPB)
+    });
+    if !terminated {
+        let offset = instructions.last().map_or(0, |last| last.offset);
+        diagnostics.push(Diagnostic { offset, kind: DiagnosticKind::MissingTerminator });
+    }
+    diagnostics
+}
+
+/// The jump target of an instruction whose target is encoded in the
+/// instruction itself rather than loaded from a register.
+pub fn static_target(instruction: &DisassembledInstruction) -> Option<i64> {
+    let Some(Token::IntegerOperand { value }) = instruction.operands.last() else {
+        return None;
+    };
+    let next_instruction = (instruction.offset + instruction.bytes.len()) as i64;
+    match instruction.opcode {
+        Opcode::JMP | Opcode::CALL => Some(*value as i64),
+        Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE => Some(*value as i64),
+        Opcode::JMPF => Some(next_instruction + *value as i64),
+        Opcode::JMPB => Some(next_instruction - *value as i64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_verify_accepts_well_formed_program() {
+        let image = assemble("main: load $0 #1\nloop: sub $1 $0 $1\ncall @done\njmpb @loop\ndone: ret").unwrap();
+        assert_eq!(verify(&image.code), vec![]);
+    }
+
+    #[test]
+    fn test_verify_reports_bad_instructions() {
+        let program = vec![
+            200, 0, 0, 0,
+            1, 40, 0, 1,
+            6, 0, 0, 2,
+            7, 0, 0, 99,
+            0, 0,
+        ];
+        assert_eq!(verify(&program), vec![
+            Diagnostic { offset: 0, kind: DiagnosticKind::UnknownOpcode { byte: 200 } },
+            Diagnostic { offset: 4, kind: DiagnosticKind::InvalidRegister { register: 40 } },
+            Diagnostic { offset: 8, kind: DiagnosticKind::MisalignedJump { target: 2 } },
+            Diagnostic { offset: 12, kind: DiagnosticKind::JumpOutOfBounds { target: 115 } },
+            Diagnostic { offset: 16, kind: DiagnosticKind::TruncatedInstruction },
+            Diagnostic { offset: 16, kind: DiagnosticKind::MissingTerminator },
+        ]);
+    }
+
+    #[test]
+    fn test_verify_float_instructions() {
+        // the assembler refuses `$f40`, so patch the register in afterwards
+        let mut code = assemble("loadf64 $f0 #1.5\njmp @end\nend: addf64 $f0 $f0 $f1\nhlt").unwrap().code;
+        code[19] = 40;
+        assert_eq!(verify(&code), vec![
+            Diagnostic { offset: 16, kind: DiagnosticKind::InvalidRegister { register: 40 } },
+        ]);
+        assert_eq!(verify(&[6, 0, 0, 8, 29, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), vec![
+            Diagnostic { offset: 0, kind: DiagnosticKind::MisalignedJump { target: 8 } },
+        ]);
+    }
+
+    #[test]
+    fn test_verify_branch_targets() {
+        let image = assemble("loop: add $0 $2 $0\nblt $0 $1 @loop\nbge $0 $1 #6\nhlt").unwrap();
+        assert_eq!(verify(&image.code), vec![
+            Diagnostic { offset: 12, kind: DiagnosticKind::MisalignedJump { target: 6 } },
+        ]);
+    }
+
+    #[test]
+    fn test_verify_requires_terminator() {
+        assert_eq!(verify(&[1, 0, 0, 1]), vec![Diagnostic { offset: 0, kind: DiagnosticKind::MissingTerminator }]);
+        assert_eq!(verify(&[]), vec![Diagnostic { offset: 0, kind: DiagnosticKind::MissingTerminator }]);
+        assert_eq!(verify(&[1, 0, 0, 1, 0, 0, 0, 0]), vec![]);
+        assert_eq!(
+            verify(&[11, 0, 0, 0])[0].to_string(),
+            "0000: program does not end with HLT, RET or an unconditional jump"
+        );
+    }
+}
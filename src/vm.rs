@@ -1,244 +1,898 @@
+use std::{error, fmt, ops::Range};
+
+use crate::image::{Image, ImageError};
+use crate::fuel::CostTable;
+use crate::history::{History, UndoEntry};
 use crate::instruction::Opcode;
+use crate::profiler::Profiler;
+use crate::snapshot::Snapshot;
+
+/// How a single step of the VM ended when it did not fault.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StepOutcome {
+    /// The instruction executed and the VM can keep going.
+    Continue,
+    /// A HLT instruction was executed.
+    Halted,
+    /// The program counter ran past the last instruction.
+    EndOfProgram,
+    /// The next instruction costs more fuel than is left. It has not been
+    /// executed, so the VM resumes there once given more fuel.
+    OutOfFuel,
+}
+
+/// A fault raised while executing an instruction. Every variant carries the
+/// offset of the faulting instruction and its decoded opcode.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    IllegalOpcode { pc: usize, opcode: Opcode, byte: u8 },
+    InvalidRegister { pc: usize, opcode: Opcode, register: u8 },
+    JumpOutOfBounds { pc: usize, opcode: Opcode, target: i64 },
+    DivisionByZero { pc: usize, opcode: Opcode },
+    UnexpectedEndOfProgram { pc: usize, opcode: Opcode },
+    StackOverflow { pc: usize, opcode: Opcode },
+    StackUnderflow { pc: usize, opcode: Opcode },
+    /// ALOC asked for a negative size or to grow the heap past `HEAP_LIMIT`.
+    AllocationFailed { pc: usize, opcode: Opcode, requested: i32 },
+    /// A memory access of `width` bytes at `address` fell outside the heap.
+    MemoryOutOfBounds { pc: usize, opcode: Opcode, address: i64, width: usize },
+    /// The result of a trapping arithmetic instruction does not fit in 32 bits.
+    ArithmeticOverflow { pc: usize, opcode: Opcode },
+}
+
+impl VmError {
+    pub fn pc(&self) -> usize {
+        match self {
+            VmError::IllegalOpcode { pc, .. }
+            | VmError::InvalidRegister { pc, .. }
+            | VmError::JumpOutOfBounds { pc, .. }
+            | VmError::DivisionByZero { pc, .. }
+            | VmError::UnexpectedEndOfProgram { pc, .. }
+            | VmError::StackOverflow { pc, .. }
+            | VmError::StackUnderflow { pc, .. }
+            | VmError::AllocationFailed { pc, .. }
+            | VmError::MemoryOutOfBounds { pc, .. }
+            | VmError::ArithmeticOverflow { pc, .. } => *pc,
+        }
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            VmError::IllegalOpcode { opcode, .. }
+            | VmError::InvalidRegister { opcode, .. }
+            | VmError::JumpOutOfBounds { opcode, .. }
+            | VmError::DivisionByZero { opcode, .. }
+            | VmError::UnexpectedEndOfProgram { opcode, .. }
+            | VmError::StackOverflow { opcode, .. }
+            | VmError::StackUnderflow { opcode, .. }
+            | VmError::AllocationFailed { opcode, .. }
+            | VmError::MemoryOutOfBounds { opcode, .. }
+            | VmError::ArithmeticOverflow { opcode, .. } => *opcode,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} at offset {}: ", self.opcode(), self.pc())?;
+        match self {
+            VmError::IllegalOpcode { byte, .. } => write!(f, "illegal opcode byte {byte:#04x}"),
+            VmError::InvalidRegister { register, .. } => write!(f, "register index {register} does not exist"),
+            VmError::JumpOutOfBounds { target, .. } => write!(f, "jump target {target} is outside the program"),
+            VmError::DivisionByZero { .. } => write!(f, "division by zero"),
+            VmError::UnexpectedEndOfProgram { .. } => write!(f, "instruction is cut off by the end of the program"),
+            VmError::StackOverflow { .. } => write!(f, "stack overflow"),
+            VmError::StackUnderflow { .. } => write!(f, "stack underflow"),
+            VmError::AllocationFailed { requested, .. } => write!(f, "cannot grow the heap by {requested} bytes"),
+            VmError::MemoryOutOfBounds { opcode, address, width, .. } => {
+                let segment = match opcode {
+                    Opcode::LOADR8 | Opcode::LOADR16 | Opcode::LOADR32 => "read-only data",
+                    _ => "heap",
+                };
+                write!(f, "{width} byte access at address {address} is outside the {segment}")
+            }
+            VmError::ArithmeticOverflow { .. } => write!(f, "arithmetic overflow"),
+        }
+    }
+}
+
+impl error::Error for VmError {}
+
+/// What ADD, SUB and MUL do when the result does not fit in 32 bits. The
+/// overflow flag is set either way; ADDW and ADDC style opcodes always wrap
+/// or trap regardless of the mode.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowMode {
+    /// Fail with `VmError::ArithmeticOverflow`.
+    Trap,
+    /// Keep the low 32 bits of the result.
+    #[default]
+    Wrap,
+    /// Clamp the result to `i32::MIN` or `i32::MAX`.
+    Saturate,
+}
+
+impl OverflowMode {
+    pub fn from_name(name: &str) -> Option<OverflowMode> {
+        match name {
+            "trap" => Some(OverflowMode::Trap),
+            "wrap" => Some(OverflowMode::Wrap),
+            "saturate" => Some(OverflowMode::Saturate),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum number of values on the value stack.
+pub const STACK_LIMIT: usize = 1024;
+/// Maximum depth of nested CALLs.
+pub const CALL_STACK_LIMIT: usize = 256;
+/// Maximum size of the heap in bytes.
+pub const HEAP_LIMIT: usize = 1 << 24;
+
 pub struct VM {
     pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
     pc: usize,
     program: Vec<u8>,
-    remainder: u32,
-    comparison_result: bool
+    ro_data: Vec<u8>,
+    /// Signed remainder of the last DIV, with the sign of the dividend.
+    remainder: i32,
+    comparison_result: bool,
+    /// Set by the last ADD, SUB or MUL family instruction when it overflowed.
+    overflow: bool,
+    overflow_mode: OverflowMode,
+    stack: Vec<i32>,
+    /// Return addresses of the active CALLs.
+    call_stack: Vec<usize>,
+    /// Byte-addressable memory grown by ALOC.
+    heap: Vec<u8>,
+    /// Offset and opcode of the instruction being executed, for error reporting.
+    instruction_start: usize,
+    current_opcode: Opcode,
+    /// Execution counts, collected only once profiling is enabled.
+    profiler: Option<Profiler>,
+    /// Fuel left to spend, or `None` to run without a limit.
+    fuel: Option<u64>,
+    fuel_used: u64,
+    costs: CostTable,
+    /// Undo log of executed instructions, kept only once recording is enabled.
+    history: Option<History>,
+    /// Heap bytes the current instruction overwrote, for the undo log.
+    overwritten: Option<(usize, Vec<u8>)>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
             registers: [0; 32],
+            float_registers: [0.0; 32],
             pc: 0,
             program: vec![],
+            ro_data: vec![],
             remainder: 0,
             comparison_result: false,
+            overflow: false,
+            overflow_mode: OverflowMode::default(),
+            stack: vec![],
+            call_stack: vec![],
+            heap: vec![],
+            instruction_start: 0,
+            current_opcode: Opcode::HLT,
+            profiler: None,
+            fuel: None,
+            fuel_used: 0,
+            costs: CostTable::default(),
+            history: None,
+            overwritten: None,
         }
     }
 
-    pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+    /// Executes instructions until the program halts, runs off its end or faults.
+    pub fn run(&mut self) -> Result<StepOutcome, VmError> {
+        loop {
+            match self.execute_instruction()? {
+                StepOutcome::Continue => (),
+                outcome => return Ok(outcome),
+            }
         }
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    pub fn run_once(&mut self) -> Result<StepOutcome, VmError> {
+        self.execute_instruction()
     }
 
-    pub fn execute_instruction(&mut self) -> bool {
+    /// Executes the next instruction, recording how to undo it when history
+    /// is enabled.
+    pub fn execute_instruction(&mut self) -> Result<StepOutcome, VmError> {
+        if self.history.is_none() {
+            return self.execute_or_rewind();
+        }
+        let registers = self.registers;
+        let float_registers = self.float_registers;
+        let mut entry = UndoEntry {
+            pc: self.pc,
+            registers: vec![],
+            float_registers: vec![],
+            comparison_result: self.comparison_result,
+            overflow: self.overflow,
+            remainder: self.remainder,
+            fuel: self.fuel,
+            fuel_used: self.fuel_used,
+            stack_length: self.stack.len(),
+            popped: self.stack.last().copied(),
+            call_stack_length: self.call_stack.len(),
+            returned_to: self.call_stack.last().copied(),
+            heap_length: self.heap.len(),
+            overwritten: None,
+        };
+        let result = self.execute_or_rewind();
+        // nothing ran, or the instruction faulted without taking effect
+        if matches!(result, Err(_) | Ok(StepOutcome::EndOfProgram | StepOutcome::OutOfFuel)) {
+            return result;
+        }
+
+        entry.registers = registers
+            .iter()
+            .zip(self.registers)
+            .enumerate()
+            .filter(|(_, (old, new))| *old != new)
+            .map(|(register, (old, _))| (register, *old))
+            .collect();
+        entry.float_registers = float_registers
+            .iter()
+            .zip(self.float_registers)
+            .enumerate()
+            .filter(|(_, (old, new))| old.to_bits() != new.to_bits())
+            .map(|(register, (old, _))| (register, *old))
+            .collect();
+        if self.stack.len() >= entry.stack_length {
+            entry.popped = None;
+        }
+        if self.call_stack.len() >= entry.call_stack_length {
+            entry.returned_to = None;
+        }
+        entry.overwritten = self.overwritten.take();
+        if let Some(history) = &mut self.history {
+            history.push(entry);
+        }
+        result
+    }
+
+    /// Executes the next instruction, moving the pc back to its start when it
+    /// faults so the caller can report the error and carry on from a whole
+    /// instruction.
+    fn execute_or_rewind(&mut self) -> Result<StepOutcome, VmError> {
+        let result = self.execute();
+        if result.is_err() {
+            self.pc = self.instruction_start;
+        }
+        result
+    }
+
+    fn execute(&mut self) -> Result<StepOutcome, VmError> {
         if self.pc >= self.program.len() {
-            return true;
+            return Ok(StepOutcome::EndOfProgram);
         }
 
-        match self.decode_opcode() {
+        let cost = self.costs.cost(Opcode::from(self.program[self.pc]));
+        if let Some(fuel) = self.fuel {
+            if cost > fuel {
+                return Ok(StepOutcome::OutOfFuel);
+            }
+            self.fuel = Some(fuel - cost);
+        }
+        self.fuel_used += cost;
+
+        self.instruction_start = self.pc;
+        self.current_opcode = self.decode_opcode();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.instruction_start, self.current_opcode);
+        }
+        match self.current_opcode {
             Opcode::HLT => {
-                println!("HLT Encountered!");
-                true
+                self.next_24_bits()?;
+                return Ok(StepOutcome::Halted);
             }
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits();
+                let register = self.next_register()?;
+                let number = self.next_16_bits()?;
                 self.registers[register] = number as i32;
-                false
-            }
-            Opcode::ADD => {
-                let number_1 = self.registers[self.next_8_bits() as usize];
-                let number_2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = number_1 + number_2;
-                false
-            }
-            Opcode::SUB => {
-                let number_1 = self.registers[self.next_8_bits() as usize];
-                let number_2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = number_1 - number_2;
-                false
-            }
-            Opcode::MUL => {
-                let number_1 = self.registers[self.next_8_bits() as usize];
-                let number_2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = number_1 * number_2;
-                false
             }
+            Opcode::ADD | Opcode::SUB | Opcode::MUL => self.arithmetic(self.overflow_mode)?,
+            Opcode::ADDW | Opcode::SUBW | Opcode::MULW => self.arithmetic(OverflowMode::Wrap)?,
+            Opcode::ADDC | Opcode::SUBC | Opcode::MULC => self.arithmetic(OverflowMode::Trap)?,
             Opcode::DIV => {
-                let number_1 = self.registers[self.next_8_bits() as usize];
-                let number_2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = number_1 / number_2;
-                self.remainder = (number_1 % number_2) as u32;
-                false
+                let number_1 = self.next_register_value()?;
+                let number_2 = self.next_register_value()?;
+                let register = self.next_register()?;
+                if number_2 == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.instruction_start, opcode: self.current_opcode });
+                }
+                self.registers[register] = number_1.wrapping_div(number_2);
+                self.remainder = number_1.wrapping_rem(number_2);
             }
             Opcode::JMP => {
                 // absolute jump
-                let step_to_jump = ((self.next_16_bits() as u32) << 8 | self.next_8_bits() as u32) as usize;
-                if step_to_jump >= self.program.len() {
-                    panic!("The instruction index: {step_to_jump} to jump is larger than the program length: {}", self.program.len());
-                }
-                self.pc = step_to_jump;
-                false
+                let step_to_jump = self.next_24_bits()? as i64;
+                self.jump_to(step_to_jump)?;
             }
             Opcode::JMPF => {
                 // relative jump forward
-                let step_to_jump = ((self.next_16_bits() as u32) << 8 | (self.next_8_bits()) as u32) as usize;
-                if self.pc + step_to_jump >= self.program.len() {
-                    panic!("The instruction index: {} to jump forward is larger than the program length: {}", self.pc + step_to_jump, self.program.len());
-                }
-                self.pc += step_to_jump;
-                false
+                let step_to_jump = self.next_24_bits()? as i64;
+                self.jump_to(self.pc as i64 + step_to_jump)?;
             }
             Opcode::JMPB => {
                 // relative jump backward
-                let step_to_jump = ((self.next_16_bits() as u32) << 8 | (self.next_8_bits()) as u32) as usize;
-                if (self.pc as i32 - step_to_jump as i32) < 0 {
-                    panic!("The instruction index: {} to jump backward is negative: {}", self.pc - step_to_jump, self.program.len());
-                }
-                self.pc -= step_to_jump;
-                false
+                let step_to_jump = self.next_24_bits()? as i64;
+                self.jump_to(self.pc as i64 - step_to_jump)?;
             }
             Opcode::EQ => {
-                let value_1 = self.registers[self.next_8_bits() as usize];
-                let value_2 = self.registers[self.next_8_bits() as usize];
-                self.next_8_bits();
-
-                if value_1 == value_2 {
-                    self.comparison_result = true;
-                } else {
-                    self.comparison_result = false;
-                }
-                false
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = value_1 == value_2;
             }
             Opcode::NEQ => {
-                let value_1 = self.registers[self.next_8_bits() as usize];
-                let value_2 = self.registers[self.next_8_bits() as usize];
-                self.next_8_bits();
-
-                if value_1 != value_2 {
-                    self.comparison_result = true;
-                } else {
-                    self.comparison_result = false;
-                }
-                false
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = value_1 != value_2;
             }
             Opcode::JEQ => {
-                let register_index = self.next_8_bits() as usize;
-                self.next_16_bits();
-
-                if register_index >= 32 {
-                    panic!("JEQ command referring to non-existing register index: {register_index}")
-                }
-                let step_to_jump = self.registers[register_index];
-                if self.comparison_result == true {
-                    if step_to_jump as usize > self.program.len() {
-                        panic!("The instruction index: {} to jump is greater than the program length: {}", step_to_jump, self.program.len());
-                    } else if step_to_jump < 0 {
-                        panic!("The instruction index: {} to jump is negative", step_to_jump);
-                    }
-                    self.pc = step_to_jump as usize;
+                let step_to_jump = self.next_register_value()?;
+                self.next_16_bits()?;
+                self.record_branch(self.comparison_result);
+                if self.comparison_result {
+                    self.jump_to(step_to_jump as i64)?;
                 }
-                false
             }
             Opcode::GEQ => {
-                let value_1 = self.registers[self.next_8_bits() as usize];
-                let value_2 = self.registers[self.next_8_bits() as usize];
-                self.next_8_bits();
-
-                if value_1 >= value_2 {
-                    self.comparison_result = true;
-                } else {
-                    self.comparison_result = false;
-                }
-                false
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = value_1 >= value_2;
             }
             Opcode::LEQ => {
-                let value_1 = self.registers[self.next_8_bits() as usize];
-                let value_2 = self.registers[self.next_8_bits() as usize];
-                self.next_8_bits();
-
-                if value_1 <= value_2 {
-                    self.comparison_result = true;
-                } else {
-                    self.comparison_result = false;
-                }
-                false
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = value_1 <= value_2;
             }
             Opcode::GT => {
-                let value_1 = self.registers[self.next_8_bits() as usize];
-                let value_2 = self.registers[self.next_8_bits() as usize];
-                self.next_8_bits();
-
-                if value_1 > value_2 {
-                    self.comparison_result = true;
-                } else {
-                    self.comparison_result = false;
-                }
-                false
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = value_1 > value_2;
             }
             Opcode::LT => {
-                let value_1 = self.registers[self.next_8_bits() as usize];
-                let value_2 = self.registers[self.next_8_bits() as usize];
-                self.next_8_bits();
-
-                if value_1 < value_2 {
-                    self.comparison_result = true;
-                } else {
-                    self.comparison_result = false;
-                }
-                false
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = value_1 < value_2;
             }
             Opcode::JNEQ => {
-                let register_index = self.next_8_bits() as usize;
-                self.next_16_bits();
-
-                if register_index >= 32 {
-                    panic!("JNEQ command referring to non-existing register index: {register_index}")
+                let step_to_jump = self.next_register_value()?;
+                self.next_16_bits()?;
+                self.record_branch(!self.comparison_result);
+                if !self.comparison_result {
+                    self.jump_to(step_to_jump as i64)?;
+                }
+            }
+            Opcode::PUSH => {
+                let value = self.next_register_value()?;
+                self.next_16_bits()?;
+                if self.stack.len() >= STACK_LIMIT {
+                    return Err(VmError::StackOverflow { pc: self.instruction_start, opcode: self.current_opcode });
+                }
+                self.stack.push(value);
+            }
+            Opcode::POP => {
+                let register = self.next_register()?;
+                self.next_16_bits()?;
+                match self.stack.pop() {
+                    Some(value) => self.registers[register] = value,
+                    None => return Err(VmError::StackUnderflow { pc: self.instruction_start, opcode: self.current_opcode }),
+                }
+            }
+            Opcode::CALL => {
+                let target = self.next_24_bits()? as i64;
+                if self.call_stack.len() >= CALL_STACK_LIMIT {
+                    return Err(VmError::StackOverflow { pc: self.instruction_start, opcode: self.current_opcode });
+                }
+                let return_address = self.pc;
+                self.jump_to(target)?;
+                self.call_stack.push(return_address);
+            }
+            Opcode::RET => {
+                self.next_24_bits()?;
+                match self.call_stack.pop() {
+                    Some(return_address) => self.pc = return_address,
+                    None => return Err(VmError::StackUnderflow { pc: self.instruction_start, opcode: self.current_opcode }),
                 }
-                let step_to_jump = self.registers[register_index];
-                if self.comparison_result == false {
-                    if step_to_jump as usize > self.program.len() {
-                        panic!("The instruction index: {} to jump is greater than the program length: {}", step_to_jump, self.program.len());
-                    } else if step_to_jump < 0 {
-                        panic!("The instruction index: {} to jump is negative", step_to_jump);
-                    }
-                    self.pc = step_to_jump as usize;
+            }
+            Opcode::ALOC => {
+                let requested = self.next_register_value()?;
+                self.next_16_bits()?;
+                let new_length = self.heap.len() as i64 + requested as i64;
+                if requested < 0 || new_length > HEAP_LIMIT as i64 {
+                    return Err(VmError::AllocationFailed { pc: self.instruction_start, opcode: self.current_opcode, requested });
                 }
-                false
+                self.heap.resize(new_length as usize, 0);
+            }
+            Opcode::LOADM8 | Opcode::LOADM16 | Opcode::LOADM32 => {
+                let register = self.next_register()?;
+                let address = self.next_register_value()?;
+                self.next_8_bits()?;
+                let width = self.memory_width();
+                let bytes = self.memory_range(address, width, self.heap.len())?;
+                self.registers[register] = self.heap[bytes].iter().fold(0u32, |value, byte| value << 8 | *byte as u32) as i32;
             }
-            other => {
-                println!("Error: Unrecognized opcode {:?}! Terminating!", other);
-                return true;
+            Opcode::LOADR8 | Opcode::LOADR16 | Opcode::LOADR32 => {
+                let register = self.next_register()?;
+                let address = self.next_register_value()?;
+                self.next_8_bits()?;
+                let width = self.memory_width();
+                let bytes = self.memory_range(address, width, self.ro_data.len())?;
+                self.registers[register] = self.ro_data[bytes].iter().fold(0u32, |value, byte| value << 8 | *byte as u32) as i32;
+            }
+            Opcode::LA => {
+                let register = self.next_register()?;
+                let address = self.next_24_bits()?;
+                self.next_24_bits()?;
+                self.registers[register] = address as i32;
+            }
+            Opcode::STOREM8 | Opcode::STOREM16 | Opcode::STOREM32 => {
+                let value = self.next_register_value()?;
+                let address = self.next_register_value()?;
+                self.next_8_bits()?;
+                let width = self.memory_width();
+                let bytes = self.memory_range(address, width, self.heap.len())?;
+                if self.history.is_some() {
+                    self.overwritten = Some((bytes.start, self.heap[bytes.clone()].to_vec()));
+                }
+                self.heap[bytes].copy_from_slice(&value.to_be_bytes()[4 - width..]);
+            }
+            Opcode::LOADHI => {
+                let register = self.next_register()?;
+                let number = self.next_16_bits()?;
+                self.registers[register] = ((number as u32) << 16 | (self.registers[register] as u32 & 0xFFFF)) as i32;
+            }
+            Opcode::LOADF64 => {
+                let register = self.next_float_register()?;
+                let number = f64::from_bits((self.next_32_bits()? as u64) << 32 | self.next_32_bits()? as u64);
+                self.next_16_bits()?;
+                self.float_registers[register] = number;
+            }
+            Opcode::ADDF64 | Opcode::SUBF64 | Opcode::MULF64 | Opcode::DIVF64 => {
+                let number_1 = self.next_float_register_value()?;
+                let number_2 = self.next_float_register_value()?;
+                self.float_registers[self.next_float_register()?] = match self.current_opcode {
+                    Opcode::ADDF64 => number_1 + number_2,
+                    Opcode::SUBF64 => number_1 - number_2,
+                    Opcode::MULF64 => number_1 * number_2,
+                    _ => number_1 / number_2,
+                };
+            }
+            Opcode::EQF64 | Opcode::NEQF64 | Opcode::GTF64 | Opcode::LTF64 | Opcode::GEQF64 | Opcode::LEQF64 => {
+                let value_1 = self.next_float_register_value()?;
+                let value_2 = self.next_float_register_value()?;
+                self.next_8_bits()?;
+                self.comparison_result = match self.current_opcode {
+                    Opcode::EQF64 => value_1 == value_2,
+                    Opcode::NEQF64 => value_1 != value_2,
+                    Opcode::GTF64 => value_1 > value_2,
+                    Opcode::LTF64 => value_1 < value_2,
+                    Opcode::GEQF64 => value_1 >= value_2,
+                    _ => value_1 <= value_2,
+                };
+            }
+            Opcode::AND | Opcode::OR | Opcode::XOR => {
+                let number_1 = self.next_register_value()?;
+                let number_2 = self.next_register_value()?;
+                self.registers[self.next_register()?] = match self.current_opcode {
+                    Opcode::AND => number_1 & number_2,
+                    Opcode::OR => number_1 | number_2,
+                    _ => number_1 ^ number_2,
+                };
+            }
+            Opcode::NOT => {
+                let number = self.next_register_value()?;
+                self.registers[self.next_register()?] = !number;
+                self.next_8_bits()?;
+            }
+            Opcode::SHL | Opcode::SHR | Opcode::SAR => {
+                // only the low five bits of the shift amount are used
+                let number = self.next_register_value()?;
+                let amount = self.next_register_value()? as u32 & 31;
+                self.registers[self.next_register()?] = match self.current_opcode {
+                    Opcode::SHL => number << amount,
+                    Opcode::SHR => ((number as u32) >> amount) as i32,
+                    _ => number >> amount,
+                };
+            }
+            Opcode::MOD => {
+                let number_1 = self.next_register_value()?;
+                let number_2 = self.next_register_value()?;
+                let register = self.next_register()?;
+                if number_2 == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.instruction_start, opcode: self.current_opcode });
+                }
+                self.registers[register] = number_1.wrapping_rem(number_2);
+            }
+            Opcode::GETREM => {
+                self.registers[self.next_register()?] = self.remainder;
+                self.next_16_bits()?;
+            }
+            Opcode::EQI | Opcode::NEQI | Opcode::GTI | Opcode::LTI | Opcode::GEQI | Opcode::LEQI => {
+                let value = self.next_register_value()?;
+                let constant = self.next_16_bits()? as i16 as i32;
+                self.comparison_result = match self.current_opcode {
+                    Opcode::EQI => value == constant,
+                    Opcode::NEQI => value != constant,
+                    Opcode::GTI => value > constant,
+                    Opcode::LTI => value < constant,
+                    Opcode::GEQI => value >= constant,
+                    _ => value <= constant,
+                };
+            }
+            Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE => {
+                // compares two registers and jumps to an absolute target,
+                // leaving the comparison flag alone
+                let value_1 = self.next_register_value()?;
+                let value_2 = self.next_register_value()?;
+                let target = self.next_24_bits()? as i64;
+                self.next_16_bits()?;
+                let taken = match self.current_opcode {
+                    Opcode::BEQ => value_1 == value_2,
+                    Opcode::BNE => value_1 != value_2,
+                    Opcode::BGT => value_1 > value_2,
+                    Opcode::BLT => value_1 < value_2,
+                    Opcode::BGE => value_1 >= value_2,
+                    _ => value_1 <= value_2,
+                };
+                self.record_branch(taken);
+                if taken {
+                    self.jump_to(target)?;
+                }
+            }
+            Opcode::SETCMP | Opcode::SETNCMP => {
+                let set = self.comparison_result == (self.current_opcode == Opcode::SETCMP);
+                self.registers[self.next_register()?] = set as i32;
+                self.next_16_bits()?;
+            }
+            Opcode::IGL => {
+                return Err(VmError::IllegalOpcode {
+                    pc: self.instruction_start,
+                    opcode: self.current_opcode,
+                    byte: self.program[self.instruction_start],
+                });
             }
         }
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Executes an ADD, SUB or MUL family instruction, handling overflow
+    /// according to `mode`.
+    fn arithmetic(&mut self, mode: OverflowMode) -> Result<(), VmError> {
+        let number_1 = self.next_register_value()?;
+        let number_2 = self.next_register_value()?;
+        let register = self.next_register()?;
+        let ((wrapped, overflowed), saturated) = match self.current_opcode {
+            Opcode::ADD | Opcode::ADDW | Opcode::ADDC => (number_1.overflowing_add(number_2), number_1.saturating_add(number_2)),
+            Opcode::SUB | Opcode::SUBW | Opcode::SUBC => (number_1.overflowing_sub(number_2), number_1.saturating_sub(number_2)),
+            _ => (number_1.overflowing_mul(number_2), number_1.saturating_mul(number_2)),
+        };
+        self.overflow = overflowed;
+        self.registers[register] = match (overflowed, mode) {
+            (true, OverflowMode::Trap) => {
+                return Err(VmError::ArithmeticOverflow { pc: self.instruction_start, opcode: self.current_opcode });
+            }
+            (true, OverflowMode::Saturate) => saturated,
+            _ => wrapped,
+        };
+        Ok(())
     }
 
     fn decode_opcode(&mut self) -> Opcode {
         let opcode = Opcode::from(self.program[self.pc]);
         self.pc += 1;
-        return opcode;
+        opcode
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        return result;
+    fn jump_to(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 || target >= self.program.len() as i64 {
+            return Err(VmError::JumpOutOfBounds { pc: self.instruction_start, opcode: self.current_opcode, target });
+        }
+        self.pc = target as usize;
+        Ok(())
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let first_part = (self.program[self.pc] as u16) << 8;
-        let second_part = self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        return first_part | second_part;
+    /// Tells the profiler, when there is one, whether the current branch was
+    /// taken.
+    fn record_branch(&mut self, taken: bool) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_branch(self.instruction_start, taken);
+        }
+    }
+
+    /// Number of bytes moved by the current LOADM, LOADR or STOREM instruction.
+    fn memory_width(&self) -> usize {
+        match self.current_opcode {
+            Opcode::LOADM8 | Opcode::LOADR8 | Opcode::STOREM8 => 1,
+            Opcode::LOADM16 | Opcode::LOADR16 | Opcode::STOREM16 => 2,
+            _ => 4,
+        }
+    }
+
+    /// The bytes a memory access touches in a heap or read-only data segment
+    /// of `length` bytes.
+    fn memory_range(&self, address: i32, width: usize, length: usize) -> Result<Range<usize>, VmError> {
+        let start = address as i64;
+        if start < 0 || start + width as i64 > length as i64 {
+            return Err(VmError::MemoryOutOfBounds { pc: self.instruction_start, opcode: self.current_opcode, address: start, width });
+        }
+        Ok(start as usize..start as usize + width)
+    }
+
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        match self.program.get(self.pc) {
+            Some(&result) => {
+                self.pc += 1;
+                Ok(result)
+            }
+            None => Err(VmError::UnexpectedEndOfProgram { pc: self.instruction_start, opcode: self.current_opcode }),
+        }
+    }
+
+    fn next_16_bits(&mut self) -> Result<u16, VmError> {
+        let first_part = (self.next_8_bits()? as u16) << 8;
+        let second_part = self.next_8_bits()? as u16;
+        Ok(first_part | second_part)
+    }
+
+    fn next_24_bits(&mut self) -> Result<u32, VmError> {
+        let first_part = (self.next_16_bits()? as u32) << 8;
+        let second_part = self.next_8_bits()? as u32;
+        Ok(first_part | second_part)
+    }
+
+    fn next_32_bits(&mut self) -> Result<u32, VmError> {
+        let first_part = (self.next_16_bits()? as u32) << 16;
+        let second_part = self.next_16_bits()? as u32;
+        Ok(first_part | second_part)
+    }
+
+    fn next_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.registers.len() {
+            return Err(VmError::InvalidRegister { pc: self.instruction_start, opcode: self.current_opcode, register });
+        }
+        Ok(register as usize)
+    }
+
+    fn next_register_value(&mut self) -> Result<i32, VmError> {
+        let register = self.next_register()?;
+        Ok(self.registers[register])
+    }
+
+    fn next_float_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.float_registers.len() {
+            return Err(VmError::InvalidRegister { pc: self.instruction_start, opcode: self.current_opcode, register });
+        }
+        Ok(register as usize)
+    }
+
+    fn next_float_register_value(&mut self) -> Result<f64, VmError> {
+        let register = self.next_float_register()?;
+        Ok(self.float_registers[register])
+    }
+
+    /// Validates an executable image and replaces the loaded program with it,
+    /// positioning the program counter at its entry point.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), ImageError> {
+        let image = Image::from_bytes(bytes)?;
+        self.program = image.code;
+        self.ro_data = image.ro_data;
+        self.pc = image.entry_point as usize;
+        Ok(())
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn comparison_result(&self) -> bool {
+        self.comparison_result
+    }
+
+    /// Remainder left by the last DIV.
+    pub fn remainder(&self) -> i32 {
+        self.remainder
+    }
+
+    /// Whether the last ADD, SUB or MUL family instruction overflowed.
+    pub fn overflow(&self) -> bool {
+        self.overflow
+    }
+
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow_mode
+    }
+
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow_mode = mode;
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    /// Captures the VM's state so it can be saved and later restored.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            pc: self.pc,
+            program: self.program.clone(),
+            ro_data: self.ro_data.clone(),
+            remainder: self.remainder,
+            comparison_result: self.comparison_result,
+            overflow: self.overflow,
+            overflow_mode: self.overflow_mode,
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            heap: self.heap.clone(),
+            fuel: self.fuel,
+            fuel_used: self.fuel_used,
+        }
+    }
+
+    /// Creates a VM that carries on from `snapshot`, including its overflow
+    /// mode, with the default cost table and profiling disabled.
+    pub fn from_snapshot(snapshot: Snapshot) -> VM {
+        VM {
+            registers: snapshot.registers,
+            float_registers: snapshot.float_registers,
+            pc: snapshot.pc,
+            program: snapshot.program,
+            ro_data: snapshot.ro_data,
+            remainder: snapshot.remainder,
+            comparison_result: snapshot.comparison_result,
+            overflow: snapshot.overflow,
+            overflow_mode: snapshot.overflow_mode,
+            stack: snapshot.stack,
+            call_stack: snapshot.call_stack,
+            heap: snapshot.heap,
+            fuel: snapshot.fuel,
+            fuel_used: snapshot.fuel_used,
+            ..VM::new()
+        }
+    }
+
+    /// Starts recording an undo log of at most `limit` instructions, so
+    /// execution can be stepped backwards. Profiling counts are not undone.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    /// Number of instructions executed since history was enabled.
+    pub fn instruction_count(&self) -> Option<u64> {
+        self.history.as_ref().map(History::instruction_count)
+    }
+
+    /// Undoes the most recently executed instruction. Returns false when
+    /// there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        for (register, value) in entry.registers {
+            self.registers[register] = value;
+        }
+        for (register, value) in entry.float_registers {
+            self.float_registers[register] = value;
+        }
+        self.pc = entry.pc;
+        self.comparison_result = entry.comparison_result;
+        self.overflow = entry.overflow;
+        self.remainder = entry.remainder;
+        self.fuel = entry.fuel;
+        self.fuel_used = entry.fuel_used;
+        self.stack.truncate(entry.stack_length);
+        self.stack.extend(entry.popped);
+        self.call_stack.truncate(entry.call_stack_length);
+        self.call_stack.extend(entry.returned_to);
+        if let Some((start, bytes)) = entry.overwritten {
+            self.heap[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+        self.heap.truncate(entry.heap_length);
+        true
+    }
+
+    /// Steps back until `count` instructions have executed since history was
+    /// enabled. Fails with the earliest reachable count when `count` has been
+    /// forgotten or lies in the future.
+    pub fn rewind_to(&mut self, count: u64) -> Result<(), u64> {
+        let Some(history) = &self.history else {
+            return Err(0);
+        };
+        if count < history.earliest() || count > history.instruction_count() {
+            return Err(history.earliest());
+        }
+        while self.instruction_count() != Some(count) {
+            self.step_back();
+        }
+        Ok(())
+    }
+
+    /// Limits execution to `fuel` more units, or removes the limit with `None`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Fuel left to spend, or `None` when execution is unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Fuel spent on every instruction executed so far, counted with or
+    /// without a limit.
+    pub fn fuel_used(&self) -> u64 {
+        self.fuel_used
+    }
+
+    pub fn set_costs(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    /// Starts counting executed instructions, discarding earlier counts.
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
     }
 
     pub fn add_byte(&mut self, byte: u8) {
         self.program.push(byte);
     }
+
+    /// Drops the program from `length` bytes on, keeping the pc inside it.
+    pub fn truncate_program(&mut self, length: usize) {
+        self.program.truncate(length);
+        self.pc = self.pc.min(self.program.len());
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::profiler::BranchCounts;
 
     #[test]
     fn test_create_vm() {
@@ -251,8 +905,8 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![0,0,0,0];
         test_vm.program = test_bytes;
-        test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.run(), Ok(StepOutcome::Halted));
+        assert_eq!(test_vm.pc, 4);
     }
 
     #[test]
@@ -260,15 +914,18 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![200,0,0,0];
         test_vm.program = test_bytes;
-        test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.run(), Err(VmError::IllegalOpcode { pc: 0, opcode: Opcode::IGL, byte: 200 }));
+        assert_eq!(test_vm.pc, 0);
+        // the operand bytes are never run as instructions of their own
+        assert_eq!(test_vm.run_once(), Err(VmError::IllegalOpcode { pc: 0, opcode: Opcode::IGL, byte: 200 }));
+        assert_eq!(test_vm.pc, 0);
     }
 
     #[test]
     fn test_load_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244];
-        test_vm.run();
+        assert_eq!(test_vm.run(), Ok(StepOutcome::EndOfProgram));
         assert_eq!(test_vm.registers[0], 500);
     }
 
@@ -276,7 +933,7 @@ mod tests {
     fn test_jmp_absolute_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![6, 0, 0, 1];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -285,7 +942,7 @@ mod tests {
     fn test_jmp_relative_forward_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![7, 0, 0, 1, 1, 0, 0, 1];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 5);
     }
 
@@ -293,7 +950,7 @@ mod tests {
     fn test_jmp_relative_backward_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![8, 0, 0, 2];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 2);
     }
 
@@ -310,12 +967,12 @@ mod tests {
                         ];
         
         // check not equal to
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.comparison_result);
 
         // check equal to
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
     }
 
     #[test]
@@ -331,12 +988,12 @@ mod tests {
                         ];
         
         // check equal to
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.comparison_result);
 
         // check not equal to
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
     }
 
     #[test]
@@ -351,12 +1008,12 @@ mod tests {
                         ];
         
         // check no jump when not equals
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
 
         // check jump when equals
         test_vm.comparison_result = true;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 10);
     }
 
@@ -375,16 +1032,16 @@ mod tests {
                         ];
         
         // check not greater than or equal to
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.comparison_result);
 
         // check greater than
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
 
         // check equals
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
     }
 
     #[test]
@@ -402,16 +1059,16 @@ mod tests {
                         ];
         
         // check not less than or equal to
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.comparison_result);
 
         // check less than
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
 
         // check equals
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
     }
 
     #[test]
@@ -426,12 +1083,12 @@ mod tests {
                         ];
         
         // check not greater than
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.comparison_result);
 
         // check greater than
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
     }
 
     #[test]
@@ -446,12 +1103,12 @@ mod tests {
                         ];
         
         // check not less than
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.comparison_result);
 
         // check less than
-        test_vm.run_once();
-        assert_eq!(test_vm.comparison_result, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.comparison_result);
     }
 
     #[test]
@@ -467,13 +1124,598 @@ mod tests {
         
         // check no jump when equals
         test_vm.comparison_result = true;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
 
         // check jump when not equals
         test_vm.comparison_result = false;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 10);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_jmp_out_of_bounds_is_an_error() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![6, 0, 0, 8];
+        assert_eq!(test_vm.run_once(), Err(VmError::JumpOutOfBounds { pc: 0, opcode: Opcode::JMP, target: 8 }));
+
+        test_vm.pc = 0;
+        test_vm.program = vec![8, 0, 0, 5];
+        assert_eq!(test_vm.run_once(), Err(VmError::JumpOutOfBounds { pc: 0, opcode: Opcode::JMPB, target: -1 }));
+    }
+
+    #[test]
+    fn test_jeq_out_of_bounds_is_an_error() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -4;
+        test_vm.comparison_result = true;
+        test_vm.program = vec![1, 1, 0, 0, 11, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.run_once(), Err(VmError::JumpOutOfBounds { pc: 4, opcode: Opcode::JEQ, target: -4 }));
+    }
+
+    #[test]
+    fn test_div_by_zero_is_an_error() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![5, 0, 1, 2];
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 0, opcode: Opcode::DIV }));
+        assert_eq!(test_vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_div_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![5, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 3);
+        assert_eq!(test_vm.remainder, 1);
+    }
+
+    #[test]
+    fn test_getrem_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![
+                            5, 0, 1, 2,
+                            54, 3, 0, 0,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -3);
+        assert_eq!(test_vm.registers[3], -1);
+        assert_eq!(test_vm.remainder(), -1);
+    }
+
+    #[test]
+    fn test_invalid_register_is_an_error() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 32, 0, 1];
+        assert_eq!(test_vm.run(), Err(VmError::InvalidRegister { pc: 0, opcode: Opcode::LOAD, register: 32 }));
+    }
+
+    #[test]
+    fn test_truncated_instruction_is_an_error() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0, 0, 1, 0];
+        test_vm.pc = 4;
+        assert_eq!(test_vm.run(), Err(VmError::UnexpectedEndOfProgram { pc: 4, opcode: Opcode::LOAD }));
+    }
+
+    #[test]
+    fn test_load_image() {
+        let mut test_vm = VM::new();
+        let image = Image {
+            entry_point: 4,
+            ro_data: vec![7],
+            code: vec![1, 0, 0, 1, 1, 0, 0, 2, 0, 0, 0, 0],
+            symbols: None,
+        };
+        test_vm.load(&image.to_bytes()).unwrap();
+        assert_eq!(test_vm.ro_data(), &[7]);
+        assert_eq!(test_vm.run(), Ok(StepOutcome::Halted));
+        assert_eq!(test_vm.registers[0], 2);
+
+        assert_eq!(test_vm.load(&[0, 1, 2]), Err(ImageError::TooShort { length: 3 }));
+    }
+
+    #[test]
+    fn test_read_only_data_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = vec![0, 0, 0xAB, 0xCD, 0x12];
+        test_vm.program = vec![
+                            69, 1, 0, 0, 2, 0, 0, 0,
+                            70, 2, 1, 0,
+                            71, 3, 1, 0,
+                            72, 4, 1, 0,
+                        ];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(test_vm.registers[1..4], [2, 0xAB, 0xABCD]);
+        assert_eq!(err, VmError::MemoryOutOfBounds { pc: 16, opcode: Opcode::LOADR32, address: 2, width: 4 });
+        assert_eq!(err.to_string(), "LOADR32 at offset 16: 4 byte access at address 2 is outside the read-only data");
+    }
+
+    #[test]
+    fn test_push_pop_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 6;
+        test_vm.program = vec![
+                            17, 0, 0, 0,
+                            17, 1, 0, 0,
+                            18, 0, 0, 0,
+                            18, 1, 0, 0,
+                        ];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.stack(), &[5, 6]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 6);
+        assert_eq!(test_vm.registers[1], 5);
+        assert!(test_vm.stack().is_empty());
+    }
+
+    #[test]
+    fn test_stack_overflow_and_underflow() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![18, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::StackUnderflow { pc: 0, opcode: Opcode::POP }));
+
+        test_vm.pc = 0;
+        test_vm.program = vec![17, 0, 0, 0, 6, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::StackOverflow { pc: 0, opcode: Opcode::PUSH }));
+        assert_eq!(test_vm.stack().len(), STACK_LIMIT);
+    }
+
+    #[test]
+    fn test_call_ret_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+                            19, 0, 0, 8,
+                            0, 0, 0, 0,
+                            1, 0, 0, 3,
+                            20, 0, 0, 0,
+                        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.call_stack(), &[4]);
+        assert_eq!(test_vm.run(), Ok(StepOutcome::Halted));
+        assert_eq!(test_vm.registers[0], 3);
+        assert!(test_vm.call_stack().is_empty());
+
+        test_vm.pc = 12;
+        assert_eq!(test_vm.run(), Err(VmError::StackUnderflow { pc: 12, opcode: Opcode::RET }));
+    }
+
+    #[test]
+    fn test_unbounded_recursion_overflows() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![19, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::StackOverflow { pc: 0, opcode: Opcode::CALL }));
+        assert_eq!(test_vm.call_stack().len(), CALL_STACK_LIMIT);
+    }
+
+    #[test]
+    fn test_aloc_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![21, 0, 0, 0, 21, 0, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap(), &[0; 16]);
+
+        test_vm.pc = 0;
+        test_vm.registers[0] = -1;
+        assert_eq!(test_vm.run(), Err(VmError::AllocationFailed { pc: 0, opcode: Opcode::ALOC, requested: -1 }));
+
+        test_vm.pc = 0;
+        test_vm.registers[0] = HEAP_LIMIT as i32;
+        assert_eq!(test_vm.run(), Err(VmError::AllocationFailed { pc: 0, opcode: Opcode::ALOC, requested: HEAP_LIMIT as i32 }));
+        assert_eq!(test_vm.heap().len(), 16);
+    }
+
+    #[test]
+    fn test_load_store_memory_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = 0x1234_5678;
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 4;
+        test_vm.program = vec![
+                            27, 0, 1, 0,
+                            25, 0, 2, 0,
+                            24, 3, 1, 0,
+                            23, 4, 1, 0,
+                            22, 5, 2, 0,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap(), &[0, 0x12, 0x34, 0x56, 0x78, 0, 0, 0]);
+        assert_eq!(test_vm.registers[3], 0x1234_5678);
+        assert_eq!(test_vm.registers[4], 0x1234);
+        assert_eq!(test_vm.registers[5], 0x78);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![24, 0, 1, 0];
+        assert_eq!(test_vm.run(), Err(VmError::MemoryOutOfBounds { pc: 0, opcode: Opcode::LOADM32, address: 2, width: 4 }));
+
+        test_vm.pc = 0;
+        test_vm.registers[1] = -1;
+        test_vm.program = vec![25, 0, 1, 0];
+        assert_eq!(test_vm.run(), Err(VmError::MemoryOutOfBounds { pc: 0, opcode: Opcode::STOREM8, address: -1, width: 1 }));
+    }
+
+    #[test]
+    fn test_loadhi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0x7777_1234;
+        test_vm.program = vec![
+                            28, 0, 0xAB, 0xCD,
+                            1, 1, 0xFF, 0xFE,
+                            28, 1, 0xFF, 0xFF,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0xABCD_1234_u32 as i32);
+        assert_eq!(test_vm.registers[1], -2);
+    }
+
+    #[test]
+    fn test_profiling_counts_instructions_and_branches() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 3;
+        test_vm.program = vec![
+                            2, 0, 1, 0,
+                            9, 0, 2, 0,
+                            16, 3, 0, 0,
+                            0, 0, 0, 0,
+                        ];
+        assert_eq!(test_vm.profiler(), None);
+        test_vm.enable_profiling();
+        test_vm.run().unwrap();
+
+        let profiler = test_vm.profiler().unwrap();
+        assert_eq!(profiler.total(), 10);
+        assert_eq!(profiler.count_at(0), 3);
+        assert_eq!(profiler.count_at(12), 1);
+        assert_eq!(profiler.opcode_count(Opcode::JNEQ), 3);
+        assert_eq!(profiler.branch_counts(8), Some(BranchCounts { taken: 2, not_taken: 1 }));
+    }
+
+    #[test]
+    fn test_fuel_stops_infinite_loop() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.set_fuel(Some(10));
+        assert_eq!(test_vm.run(), Ok(StepOutcome::OutOfFuel));
+        assert_eq!(test_vm.fuel(), Some(0));
+        assert_eq!(test_vm.fuel_used(), 10);
+    }
+
+    #[test]
+    fn test_fuel_uses_cost_table() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![
+                            4, 0, 1, 2,
+                            5, 0, 1, 2,
+                            0, 0, 0, 0,
+                        ];
+        let mut costs = CostTable::uniform();
+        costs.set(Opcode::DIV, 5);
+        test_vm.set_costs(costs);
+        test_vm.set_fuel(Some(5));
+        assert_eq!(test_vm.run(), Ok(StepOutcome::OutOfFuel));
+        assert_eq!(test_vm.pc(), 4);
+        assert_eq!(test_vm.fuel(), Some(4));
+
+        test_vm.set_fuel(Some(6));
+        assert_eq!(test_vm.run(), Ok(StepOutcome::Halted));
+        assert_eq!(test_vm.fuel(), Some(0));
+        assert_eq!(test_vm.fuel_used(), 7);
+    }
+
+    #[test]
+    fn test_snapshot_resumes_execution() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 5;
+        test_vm.program = vec![
+                            2, 0, 1, 0,
+                            17, 0, 0, 0,
+                            9, 0, 2, 0,
+                            16, 3, 0, 0,
+                            0, 0, 0, 0,
+                        ];
+        test_vm.set_fuel(Some(6));
+        assert_eq!(test_vm.run(), Ok(StepOutcome::OutOfFuel));
+
+        let bytes = test_vm.snapshot().to_bytes();
+        let mut restored = VM::from_snapshot(Snapshot::from_bytes(&bytes).unwrap());
+        assert_eq!(restored.snapshot(), test_vm.snapshot());
+
+        restored.set_fuel(None);
+        test_vm.set_fuel(None);
+        assert_eq!(restored.run(), Ok(StepOutcome::Halted));
+        assert_eq!(test_vm.run(), Ok(StepOutcome::Halted));
+        assert_eq!(restored.snapshot(), test_vm.snapshot());
+        assert_eq!(restored.stack(), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_step_back_restores_state() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 8;
+        test_vm.registers[2] = 0x0102;
+        test_vm.float_registers[1] = 1.5;
+        test_vm.program = vec![
+                            21, 1, 0, 0,
+                            26, 2, 3, 0,
+                            17, 1, 0, 0,
+                            18, 4, 0, 0,
+                            19, 0, 0, 32,
+                            5, 1, 2, 5,
+                            30, 1, 1, 1,
+                            0, 0, 0, 0,
+                            9, 1, 1, 0,
+                            20, 0, 0, 0,
+                        ];
+        test_vm.enable_history(100);
+        let mut states = vec![test_vm.snapshot()];
+        while test_vm.run_once() == Ok(StepOutcome::Continue) {
+            states.push(test_vm.snapshot());
+        }
+        assert_eq!(test_vm.instruction_count(), Some(10));
+
+        test_vm.step_back();
+        while let Some(state) = states.pop() {
+            assert_eq!(test_vm.snapshot(), state);
+            test_vm.step_back();
+        }
+        assert!(!test_vm.step_back());
+        assert_eq!(test_vm.instruction_count(), Some(0));
+    }
+
+    #[test]
+    fn test_history_skips_faulting_instructions() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 1, 0, 5, 200, 0, 0, 0];
+        test_vm.enable_history(100);
+        assert!(test_vm.run().is_err());
+        assert_eq!(test_vm.instruction_count(), Some(1));
+
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.registers[1], 0);
+        assert_eq!(test_vm.pc(), 0);
+        assert!(!test_vm.step_back());
+    }
+
+    #[test]
+    fn test_rewind_to() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![2, 0, 1, 0, 6, 0, 0, 0];
+        test_vm.enable_history(4);
+        for _ in 0..10 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.rewind_to(7), Ok(()));
+        assert_eq!(test_vm.registers[0], 4);
+        assert_eq!(test_vm.pc(), 4);
+        assert_eq!(test_vm.rewind_to(5), Err(6));
+        assert_eq!(test_vm.rewind_to(8), Err(6));
+        assert_eq!(VM::new().rewind_to(0), Err(0));
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[1] = 1.5;
+        test_vm.float_registers[2] = 0.25;
+        test_vm.program = vec![
+                            30, 1, 2, 3,
+                            31, 1, 2, 4,
+                            32, 1, 2, 5,
+                            33, 1, 2, 6,
+                            33, 1, 0, 7,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(&test_vm.float_registers[3..8], &[1.75, 1.25, 0.375, 6.0, f64::INFINITY]);
+    }
+
+    #[test]
+    fn test_loadf64_opcode() {
+        let mut test_vm = VM::new();
+        let mut program = vec![29, 4];
+        program.extend_from_slice(&(-0.1f64).to_be_bytes());
+        program.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        test_vm.program = program;
+        assert_eq!(test_vm.run(), Ok(StepOutcome::Halted));
+        assert_eq!(test_vm.float_registers[4], -0.1);
+        assert_eq!(test_vm.pc(), 16);
+
+        test_vm.pc = 0;
+        test_vm.program = vec![29, 40, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::InvalidRegister { pc: 0, opcode: Opcode::LOADF64, register: 40 }));
+        test_vm.pc = 0;
+        test_vm.program = vec![29, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::UnexpectedEndOfProgram { pc: 0, opcode: Opcode::LOADF64 }));
+    }
+
+    #[test]
+    fn test_float_comparison_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[1] = 2.0;
+        test_vm.float_registers[2] = f64::NAN;
+        let cases = [
+            (34, 0, 0, true),
+            (35, 1, 0, true),
+            (36, 1, 0, true),
+            (37, 1, 0, false),
+            (38, 0, 0, true),
+            (39, 1, 0, false),
+            (34, 2, 2, false),
+            (35, 2, 2, true),
+        ];
+        for (opcode, first, second, expected) in cases {
+            test_vm.pc = 0;
+            test_vm.program = vec![opcode, first, second, 0];
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.comparison_result, expected, "opcode {opcode} ${first} ${second}");
+        }
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 0b1100;
+        test_vm.registers[2] = 0b1010;
+        test_vm.program = vec![
+                            40, 1, 2, 3,
+                            41, 1, 2, 4,
+                            42, 1, 2, 5,
+                            43, 1, 6, 0,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(&test_vm.registers[3..7], &[0b1000, 0b1110, 0b0110, !0b1100]);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = -16;
+        test_vm.registers[2] = 2;
+        test_vm.registers[3] = 33;
+        test_vm.program = vec![
+                            44, 1, 2, 4,
+                            45, 1, 2, 5,
+                            46, 1, 2, 6,
+                            44, 2, 3, 7,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[4], -64);
+        assert_eq!(test_vm.registers[5], 0x3FFF_FFFC);
+        assert_eq!(test_vm.registers[6], -4);
+        assert_eq!(test_vm.registers[7], 4);
+    }
+
+    #[test]
+    fn test_compare_immediate_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = -5;
+        // -5 > -6, then -5 == 7
+        test_vm.program = vec![
+                            57, 1, 0xFF, 0xFA,
+                            61, 2, 0, 0,
+                            55, 1, 0, 7,
+                            61, 3, 0, 0,
+                            62, 4, 0, 0,
+                        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2..5], [1, 0, 1]);
+        assert!(!test_vm.comparison_result);
+    }
+
+    #[test]
+    fn test_branch_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 1;
+        // counts $0 up to $1 with a BLT loop
+        test_vm.program = vec![
+                            2, 0, 2, 0,
+                            66, 0, 1, 0, 0, 0, 0, 0,
+                            0, 0, 0, 0,
+                        ];
+        test_vm.enable_profiling();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 3);
+        assert_eq!(test_vm.profiler().unwrap().branch_counts(4).map(|counts| (counts.taken, counts.not_taken)), Some((2, 1)));
+        assert!(!test_vm.comparison_result);
+
+        test_vm.program = vec![63, 0, 0, 0, 0, 64, 0, 0];
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Err(VmError::JumpOutOfBounds { pc: 0, opcode: Opcode::BEQ, target: 64 }));
+    }
+
+    #[test]
+    fn test_mod_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = -7;
+        test_vm.registers[2] = 3;
+        test_vm.registers[3] = i32::MIN;
+        test_vm.registers[4] = -1;
+        test_vm.program = vec![
+                            47, 1, 2, 5,
+                            47, 3, 4, 6,
+                            47, 1, 0, 7,
+                        ];
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 8, opcode: Opcode::MOD }));
+        assert_eq!(test_vm.registers[5], -1);
+        assert_eq!(test_vm.registers[6], 0);
+    }
+
+    #[test]
+    fn test_overflow_modes() {
+        let program = vec![
+                            2, 1, 2, 3,
+                            3, 4, 2, 5,
+                            4, 1, 1, 6,
+                            2, 2, 2, 7,
+                        ];
+        let run = |mode| {
+            let mut test_vm = VM::new();
+            test_vm.registers[1] = i32::MAX;
+            test_vm.registers[2] = 1;
+            test_vm.registers[4] = i32::MIN;
+            test_vm.program = program.clone();
+            test_vm.set_overflow_mode(mode);
+            (test_vm.run(), test_vm)
+        };
+
+        let (result, test_vm) = run(OverflowMode::Wrap);
+        assert_eq!(result, Ok(StepOutcome::EndOfProgram));
+        assert_eq!(&test_vm.registers[3..7], &[i32::MIN, i32::MIN, i32::MAX, 1]);
+        assert!(!test_vm.overflow());
+
+        let (result, test_vm) = run(OverflowMode::Saturate);
+        assert_eq!(result, Ok(StepOutcome::EndOfProgram));
+        assert_eq!(&test_vm.registers[3..7], &[i32::MAX, i32::MIN, i32::MIN, i32::MAX]);
+
+        let (result, test_vm) = run(OverflowMode::Trap);
+        assert_eq!(result, Err(VmError::ArithmeticOverflow { pc: 0, opcode: Opcode::ADD }));
+        assert!(test_vm.overflow());
+        assert_eq!(test_vm.registers[3], 0);
+    }
+
+    #[test]
+    fn test_explicit_overflow_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.set_overflow_mode(OverflowMode::Trap);
+        test_vm.registers[1] = i32::MIN;
+        test_vm.registers[2] = -1;
+        test_vm.program = vec![
+                            48, 2, 2, 3,
+                            50, 1, 2, 4,
+                            49, 1, 2, 5,
+                            53, 1, 2, 6,
+                        ];
+        assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 12, opcode: Opcode::MULC }));
+        assert_eq!(test_vm.registers[3], -2);
+        assert_eq!(test_vm.registers[4], i32::MIN);
+        assert_eq!(test_vm.registers[5], i32::MIN + 1);
+
+        test_vm.pc = 0;
+        test_vm.program = vec![51, 1, 2, 3];
+        assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 0, opcode: Opcode::ADDC }));
+        test_vm.set_overflow_mode(OverflowMode::Saturate);
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 0, opcode: Opcode::ADDC }));
+    }
+}